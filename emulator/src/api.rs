@@ -0,0 +1,483 @@
+//! Emulation of the `/3/device/<token>` endpoint.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use apns2::{Priority, PushType};
+use hyper::http::request::Parts;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Provider tokens older than this are rejected with ExpiredProviderToken.
+const TOKEN_MAX_AGE: u64 = 60 * 60;
+
+pub struct Config {
+    /// Device tokens answered with 410 Unregistered.
+    pub unregistered: HashSet<String>,
+    /// Device tokens answered with 400 BadDeviceToken, as if they belonged
+    /// to the other environment.
+    pub bad_device_tokens: HashSet<String>,
+    /// Key ids of provider tokens answered with 403 InvalidProviderToken.
+    pub revoked_keys: HashSet<String>,
+    /// Number of requests per connection, after which requests are answered
+    /// with 503 Shutdown and the connection is closed with GOAWAY.
+    pub shutdown_after: Option<usize>,
+    /// Minimum interval between notifications to the same device, after
+    /// which 429 TooManyRequests is returned.
+    pub token_interval: Option<Duration>,
+    /// Number of received notifications kept for inspection.
+    pub history: usize,
+}
+
+pub struct State {
+    config: Config,
+    pushes: Mutex<VecDeque<Value>>,
+    last_push: Mutex<HashMap<String, Instant>>,
+}
+
+impl State {
+    pub fn new(config: Config) -> Self {
+        State {
+            config,
+            pushes: Mutex::new(VecDeque::new()),
+            last_push: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// All recorded notifications, oldest first.
+    pub fn pushes(&self) -> Vec<Value> {
+        self.pushes.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.pushes.lock().unwrap().clear();
+    }
+
+    fn record(&self, push: Value) {
+        println!("{}", push);
+        let mut pushes = self.pushes.lock().unwrap();
+        pushes.push_back(push);
+        while pushes.len() > self.config.history {
+            pushes.pop_front();
+        }
+    }
+}
+
+/// Information about the TLS connection a request was received on.
+pub struct Connection {
+    /// Common name of the client certificate, if one was presented.
+    pub client_certificate: Option<String>,
+    /// Number of requests received on the connection.
+    requests: AtomicUsize,
+    /// Notified when the connection should be shut down.
+    pub shutdown: Notify,
+}
+
+impl Connection {
+    pub fn new(client_certificate: Option<String>) -> Self {
+        Connection {
+            client_certificate,
+            requests: AtomicUsize::new(0),
+            shutdown: Notify::new(),
+        }
+    }
+}
+
+/// An error response.
+struct Rejection {
+    status: StatusCode,
+    reason: &'static str,
+}
+
+fn reject(status: StatusCode, reason: &'static str) -> Rejection {
+    Rejection { status, reason }
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn header<'a>(parts: &'a Parts, name: &str) -> Option<&'a str> {
+    parts.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Check a provider token (JWT) for structure and age, and reject revoked
+/// keys.
+///
+/// The signature is not verified, since the emulator does not know the
+/// public keys of the provider.
+fn check_token(token: &str, config: &Config) -> Result<(), Rejection> {
+    let invalid = || reject(StatusCode::FORBIDDEN, "InvalidProviderToken");
+    let decode = |part: &str| {
+        base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Value>(&data).ok())
+    };
+
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(invalid());
+    }
+    let header = decode(parts[0]).ok_or_else(invalid)?;
+    let claims = decode(parts[1]).ok_or_else(invalid)?;
+    if header["alg"] != "ES256" || !header["kid"].is_string() || !claims["iss"].is_string() {
+        return Err(invalid());
+    }
+    if header["kid"].as_str().is_some_and(|kid| config.revoked_keys.contains(kid)) {
+        return Err(invalid());
+    }
+
+    let issued_at = claims["iat"].as_u64().ok_or_else(invalid)?;
+    let now = unix_time().as_secs();
+    if issued_at > now + 60 {
+        return Err(invalid());
+    }
+    if issued_at + TOKEN_MAX_AGE < now {
+        return Err(reject(StatusCode::FORBIDDEN, "ExpiredProviderToken"));
+    }
+    Ok(())
+}
+
+/// Validate the request headers and authentication.
+fn check_headers(
+    parts: &Parts,
+    conn: &Connection,
+    config: &Config,
+    push: &mut serde_json::Map<String, Value>,
+) -> Result<(), Rejection> {
+    if parts.method != Method::POST {
+        return Err(reject(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed"));
+    }
+    let device_token = match parts.uri.path().strip_prefix("/3/device/") {
+        Some(token) if !token.is_empty() && !token.contains('/') => token,
+        Some(_) => return Err(reject(StatusCode::BAD_REQUEST, "MissingDeviceToken")),
+        None => return Err(reject(StatusCode::NOT_FOUND, "BadPath")),
+    };
+    push.insert("device_token".into(), device_token.into());
+    if device_token.len() < 64
+        || device_token.len() > 200
+        || !device_token.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(reject(StatusCode::BAD_REQUEST, "BadDeviceToken"));
+    }
+
+    let topic = header(parts, "apns-topic");
+    push.insert("topic".into(), topic.into());
+    match header(parts, "authorization") {
+        Some(auth) => {
+            push.insert("auth".into(), "token".into());
+            let token = auth
+                .strip_prefix("bearer ")
+                .ok_or_else(|| reject(StatusCode::FORBIDDEN, "InvalidProviderToken"))?;
+            check_token(token, config)?;
+            if topic.is_none() {
+                return Err(reject(StatusCode::BAD_REQUEST, "MissingTopic"));
+            }
+        }
+        None => match conn.client_certificate {
+            Some(ref subject) => {
+                push.insert("auth".into(), "certificate".into());
+                push.insert("certificate".into(), subject.as_str().into());
+            }
+            None => return Err(reject(StatusCode::FORBIDDEN, "MissingProviderToken")),
+        },
+    }
+
+    if let Some(push_type) = header(parts, "apns-push-type") {
+        push.insert("push_type".into(), push_type.into());
+        if serde_json::from_value::<PushType>(push_type.into()).is_err() {
+            return Err(reject(StatusCode::BAD_REQUEST, "InvalidPushType"));
+        }
+    }
+    if let Some(priority) = header(parts, "apns-priority") {
+        push.insert("priority".into(), priority.into());
+        if !["1", "5", "10"].contains(&priority) {
+            return Err(reject(StatusCode::BAD_REQUEST, "BadPriority"));
+        }
+    }
+    if let Some(expiration) = header(parts, "apns-expiration") {
+        push.insert("expiration".into(), expiration.into());
+        if expiration.parse::<u64>().is_err() {
+            return Err(reject(StatusCode::BAD_REQUEST, "BadExpirationDate"));
+        }
+    }
+    if let Some(collapse_id) = header(parts, "apns-collapse-id") {
+        push.insert("collapse_id".into(), collapse_id.into());
+        if collapse_id.len() > 64 {
+            return Err(reject(StatusCode::BAD_REQUEST, "BadCollapseId"));
+        }
+    }
+    Ok(())
+}
+
+/// Validate the payload, recording any issues found by the client library
+/// validation.
+fn check_payload(
+    body: &[u8],
+    push: &mut serde_json::Map<String, Value>,
+) -> Result<(), Rejection> {
+    if body.is_empty() {
+        return Err(reject(StatusCode::BAD_REQUEST, "PayloadEmpty"));
+    }
+    let payload: Value = serde_json::from_slice(body)
+        .map_err(|_| reject(StatusCode::BAD_REQUEST, "PayloadEmpty"))?;
+    push.insert("payload".into(), payload.clone());
+    if !payload["aps"].is_object() {
+        return Err(reject(StatusCode::BAD_REQUEST, "PayloadEmpty"));
+    }
+
+    let push_type = push
+        .get("push_type")
+        .and_then(|t| serde_json::from_value(t.clone()).ok());
+    let priority = match push.get("priority").and_then(|p| p.as_str()) {
+        Some("5") => Some(Priority::Low),
+        Some("10") => Some(Priority::High),
+        _ => None,
+    };
+    let max_size = match push_type {
+        Some(PushType::Voip) => apns2::MAX_VOIP_PAYLOAD_SIZE,
+        _ => apns2::MAX_PAYLOAD_SIZE,
+    };
+    if body.len() > max_size {
+        return Err(reject(StatusCode::PAYLOAD_TOO_LARGE, "PayloadTooLarge"));
+    }
+
+    let issues = apns2::validate_payload(&payload, push_type, priority);
+    if !issues.is_empty() {
+        push.insert("issues".into(), json!(issues));
+    }
+    Ok(())
+}
+
+impl State {
+    /// Apply the device specific rules: unregistered and bad tokens, and
+    /// throttling.
+    fn check_device(&self, device_token: &str) -> Result<(), Rejection> {
+        if self.config.bad_device_tokens.contains(device_token) {
+            return Err(reject(StatusCode::BAD_REQUEST, "BadDeviceToken"));
+        }
+        if self.config.unregistered.contains(device_token) {
+            return Err(reject(StatusCode::GONE, "Unregistered"));
+        }
+        if let Some(interval) = self.config.token_interval {
+            let now = Instant::now();
+            let mut last_push = self.last_push.lock().unwrap();
+            if let Some(last) = last_push.get(device_token) {
+                if now.duration_since(*last) < interval {
+                    return Err(reject(StatusCode::TOO_MANY_REQUESTS, "TooManyRequests"));
+                }
+            }
+            last_push.insert(device_token.to_string(), now);
+        }
+        Ok(())
+    }
+}
+
+/// Handle a request to the API.
+pub async fn handle(
+    state: Arc<State>,
+    conn: Arc<Connection>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let mut push = serde_json::Map::new();
+    push.insert("received_at".into(), (unix_time().as_millis() as u64).into());
+
+    let id = match header(&parts, "apns-id") {
+        None => Ok(Uuid::new_v4()),
+        Some(id) => Uuid::parse_str(id).map_err(|_| reject(StatusCode::BAD_REQUEST, "BadMessageId")),
+    };
+    let apns_id = id.as_ref().map(|id| id.to_string()).unwrap_or_default();
+    push.insert("apns_id".into(), apns_id.as_str().into());
+
+    let requests = conn.requests.fetch_add(1, Ordering::SeqCst) + 1;
+    let result = match id {
+        Ok(_) if state.config.shutdown_after.is_some_and(|limit| requests > limit) => {
+            conn.shutdown.notify_one();
+            Err(reject(StatusCode::SERVICE_UNAVAILABLE, "Shutdown"))
+        }
+        Ok(_) => check_headers(&parts, &conn, &state.config, &mut push),
+        Err(e) => Err(e),
+    };
+    let result = match (result, hyper::body::to_bytes(body).await) {
+        (Ok(()), Ok(body)) => check_payload(&body, &mut push),
+        (Ok(()), Err(_)) => Err(reject(StatusCode::BAD_REQUEST, "PayloadEmpty")),
+        (Err(e), _) => Err(e),
+    };
+    let result = result.and_then(|()| {
+        let device_token = push["device_token"].as_str().unwrap_or_default().to_string();
+        state.check_device(&device_token)
+    });
+
+    let response = Response::builder().header("apns-id", apns_id);
+    let response = match result {
+        Ok(()) => {
+            push.insert("status".into(), 200.into());
+            response.status(StatusCode::OK).body(Body::empty())
+        }
+        Err(rejection) => {
+            push.insert("status".into(), rejection.status.as_u16().into());
+            push.insert("reason".into(), rejection.reason.into());
+            let body = if rejection.status == StatusCode::GONE {
+                json!({ "reason": rejection.reason, "timestamp": unix_time().as_millis() as u64 })
+            } else {
+                json!({ "reason": rejection.reason })
+            };
+            response
+                .status(rejection.status)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+        }
+    };
+    state.record(Value::Object(push));
+    Ok(response.expect("Valid response"))
+}
+
+/// Handle a request to the inspection endpoint.
+///
+/// `GET /pushes` returns the recorded notifications, `DELETE /pushes`
+/// clears them.
+pub async fn inspect(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/pushes") => Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(Value::Array(state.pushes()).to_string())),
+        (&Method::DELETE, "/pushes") => {
+            state.clear();
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("Valid response"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DEVICE_TOKEN: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn encode(value: Value) -> String {
+        base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /// A provider token with the given header and claims. The signature is
+    /// not checked.
+    fn token(header: Value, claims: Value) -> String {
+        format!("{}.{}.c2ln", encode(header), encode(claims))
+    }
+
+    fn valid_token() -> String {
+        let iat = unix_time().as_secs();
+        token(
+            json!({ "alg": "ES256", "kid": "KEYID" }),
+            json!({ "iss": "TEAMID", "iat": iat }),
+        )
+    }
+
+    fn config() -> Config {
+        Config {
+            unregistered: HashSet::new(),
+            bad_device_tokens: HashSet::new(),
+            revoked_keys: ["REVOKED".to_string()].iter().cloned().collect(),
+            shutdown_after: None,
+            token_interval: None,
+            history: 10,
+        }
+    }
+
+    fn reason(result: Result<(), Rejection>) -> Option<&'static str> {
+        result.err().map(|r| r.reason)
+    }
+
+    fn parts(method: Method, path: &str, headers: &[(&str, &str)]) -> Parts {
+        let mut request = Request::builder().method(method).uri(path);
+        for &(name, value) in headers {
+            request = request.header(name, value);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    fn check(parts: &Parts, client_certificate: Option<&str>) -> Result<(), Rejection> {
+        let conn = Connection::new(client_certificate.map(String::from));
+        check_headers(parts, &conn, &config(), &mut serde_json::Map::new())
+    }
+
+    #[test]
+    fn test_check_token() {
+        let now = unix_time().as_secs();
+        let header = json!({ "alg": "ES256", "kid": "KEYID" });
+        let check_token = |token: &str| check_token(token, &config());
+
+        assert_eq!(reason(check_token(&valid_token())), None);
+        assert_eq!(reason(check_token("a.b")), Some("InvalidProviderToken"));
+        assert_eq!(reason(check_token("a.b.c")), Some("InvalidProviderToken"));
+        let hs256 = token(
+            json!({ "alg": "HS256", "kid": "KEYID" }),
+            json!({ "iss": "TEAMID", "iat": now }),
+        );
+        assert_eq!(reason(check_token(&hs256)), Some("InvalidProviderToken"));
+        let no_issuer = token(header.clone(), json!({ "iat": now }));
+        assert_eq!(
+            reason(check_token(&no_issuer)),
+            Some("InvalidProviderToken")
+        );
+        let future = token(
+            header.clone(),
+            json!({ "iss": "TEAMID", "iat": now + 3600 }),
+        );
+        assert_eq!(reason(check_token(&future)), Some("InvalidProviderToken"));
+        let expired = token(
+            header,
+            json!({ "iss": "TEAMID", "iat": now - 2 * TOKEN_MAX_AGE }),
+        );
+        assert_eq!(reason(check_token(&expired)), Some("ExpiredProviderToken"));
+        let revoked = token(
+            json!({ "alg": "ES256", "kid": "REVOKED" }),
+            json!({ "iss": "TEAMID", "iat": now }),
+        );
+        assert_eq!(reason(check_token(&revoked)), Some("InvalidProviderToken"));
+    }
+
+    #[test]
+    fn test_check_headers() {
+        let path = format!("/3/device/{}", DEVICE_TOKEN);
+        let bearer = format!("bearer {}", valid_token());
+        let topic = ("apns-topic", "com.example.app");
+        let auth = ("authorization", bearer.as_str());
+        let post = |headers: &[(&str, &str)]| {
+            reason(check(&parts(Method::POST, &path, headers), None))
+        };
+
+        assert_eq!(post(&[topic, auth, ("apns-priority", "10")]), None);
+        let certificate = parts(Method::POST, &path, &[]);
+        assert_eq!(reason(check(&certificate, Some("Apple Push Services"))), None);
+
+        let get = parts(Method::GET, &path, &[topic, auth]);
+        assert_eq!(reason(check(&get, None)), Some("MethodNotAllowed"));
+        let bad_path = parts(Method::POST, "/3/other", &[topic, auth]);
+        assert_eq!(reason(check(&bad_path, None)), Some("BadPath"));
+        let no_device = parts(Method::POST, "/3/device/", &[topic, auth]);
+        assert_eq!(reason(check(&no_device, None)), Some("MissingDeviceToken"));
+        let bad_device = parts(Method::POST, "/3/device/xyz", &[topic, auth]);
+        assert_eq!(reason(check(&bad_device, None)), Some("BadDeviceToken"));
+
+        assert_eq!(post(&[topic]), Some("MissingProviderToken"));
+        assert_eq!(post(&[topic, ("authorization", "basic x")]), Some("InvalidProviderToken"));
+        assert_eq!(post(&[auth]), Some("MissingTopic"));
+        assert_eq!(post(&[topic, auth, ("apns-push-type", "fax")]), Some("InvalidPushType"));
+        assert_eq!(post(&[topic, auth, ("apns-priority", "7")]), Some("BadPriority"));
+        assert_eq!(post(&[topic, auth, ("apns-expiration", "soon")]), Some("BadExpirationDate"));
+    }
+}
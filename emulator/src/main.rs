@@ -0,0 +1,220 @@
+//! A local emulator of the APNS api for development and staging.
+//!
+//! Serves `/3/device/<token>` over HTTP/2 with TLS, accepts certificate or
+//! token authentication and answers with the error semantics of the real
+//! api. Received notifications are printed to stdout as JSON lines, and can
+//! be inspected over a separate plain HTTP endpoint.
+
+mod api;
+mod tls;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{App, Arg};
+use hyper::server::conn::Http;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Server;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{Ssl, SslAcceptor};
+use openssl::x509::X509;
+use tokio::net::TcpListener;
+use tokio_openssl::SslStream;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Serve a single TLS connection.
+async fn serve_connection(
+    acceptor: Arc<SslAcceptor>,
+    state: Arc<api::State>,
+    tcp: tokio::net::TcpStream,
+) -> Result<(), Error> {
+    let ssl = Ssl::new(acceptor.context())?;
+    let mut stream = SslStream::new(ssl, tcp)?;
+    Pin::new(&mut stream).accept().await?;
+
+    let client_certificate = stream.ssl().peer_certificate().map(|cert| {
+        cert.subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|e| e.data().to_string().ok())
+            .unwrap_or_default()
+    });
+    let conn = Arc::new(api::Connection::new(client_certificate));
+
+    let shutdown = conn.clone();
+    let service = service_fn(move |req| api::handle(state.clone(), conn.clone(), req));
+    let http = Http::new().serve_connection(stream, service);
+    tokio::pin!(http);
+    tokio::select! {
+        result = &mut http => return Ok(result?),
+        _ = shutdown.shutdown.notified() => {}
+    }
+    // Answer the requests in flight, then close the connection with GOAWAY.
+    http.as_mut().graceful_shutdown();
+    http.await?;
+    Ok(())
+}
+
+async fn run(
+    addr: SocketAddr,
+    inspect: Option<SocketAddr>,
+    acceptor: SslAcceptor,
+    state: Arc<api::State>,
+) -> Result<(), Error> {
+    if let Some(addr) = inspect {
+        let state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move { Ok::<_, hyper::Error>(service_fn(move |req| api::inspect(state.clone(), req))) }
+        });
+        let server = Server::try_bind(&addr)?.serve(make_service);
+        eprintln!("Inspection endpoint listening on http://{}/pushes", addr);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                eprintln!("Inspection endpoint failed: {}", e);
+            }
+        });
+    }
+
+    let acceptor = Arc::new(acceptor);
+    let listener = TcpListener::bind(addr).await?;
+    eprintln!("APNS emulator listening on https://{}", addr);
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(acceptor, state, tcp).await {
+                eprintln!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let matches = App::new("apns2-emulator")
+        .about("Local emulator of the Apple Push Notification service api")
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .default_value("127.0.0.1:8443")
+                .help("Address to serve the api on"),
+        )
+        .arg(
+            Arg::with_name("inspect")
+                .long("inspect")
+                .value_name("ADDR")
+                .help("Address to serve the inspection endpoint (GET/DELETE /pushes) on"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .value_name("PATH")
+                .requires("tls-key")
+                .help("PEM server certificate (default: generate a self signed one)"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .value_name("PATH")
+                .requires("tls-cert")
+                .help("PEM private key for the server certificate"),
+        )
+        .arg(
+            Arg::with_name("write-cert")
+                .long("write-cert")
+                .value_name("PATH")
+                .help("Write the server certificate to a PEM file, for clients to trust"),
+        )
+        .arg(
+            Arg::with_name("unregistered")
+                .long("unregistered")
+                .value_name("DEVICE_TOKEN")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Answer notifications for this device token with 410 Unregistered"),
+        )
+        .arg(
+            Arg::with_name("bad-device-token")
+                .long("bad-device-token")
+                .value_name("DEVICE_TOKEN")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Answer notifications for this device token with 400 BadDeviceToken"),
+        )
+        .arg(
+            Arg::with_name("revoked-key")
+                .long("revoked-key")
+                .value_name("KEY_ID")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Answer provider tokens of this key with 403 InvalidProviderToken"),
+        )
+        .arg(
+            Arg::with_name("shutdown-after")
+                .long("shutdown-after")
+                .value_name("N")
+                .help("Answer with 503 Shutdown and close connections after N requests"),
+        )
+        .arg(
+            Arg::with_name("token-interval")
+                .long("token-interval")
+                .value_name("MILLISECONDS")
+                .help("Answer with 429 TooManyRequests if a device is notified more often"),
+        )
+        .arg(
+            Arg::with_name("history")
+                .long("history")
+                .value_name("N")
+                .default_value("1000")
+                .help("Number of notifications kept for inspection"),
+        )
+        .get_matches();
+
+    let (cert, key) = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert), Some(key)) => (
+            X509::from_pem(&fs::read(cert)?)?,
+            PKey::private_key_from_pem(&fs::read(key)?)?,
+        ),
+        _ => tls::self_signed()?,
+    };
+    if let Some(path) = matches.value_of("write-cert") {
+        fs::write(path, cert.to_pem()?)?;
+    }
+    let acceptor = tls::acceptor(&cert, &key)?;
+
+    let values = |name| {
+        matches
+            .values_of(name)
+            .map(|v| v.map(|t| t.to_string()).collect())
+            .unwrap_or_default()
+    };
+    let config = api::Config {
+        unregistered: values("unregistered"),
+        bad_device_tokens: values("bad-device-token"),
+        revoked_keys: values("revoked-key"),
+        shutdown_after: match matches.value_of("shutdown-after") {
+            Some(n) => Some(n.parse()?),
+            None => None,
+        },
+        token_interval: match matches.value_of("token-interval") {
+            Some(ms) => Some(Duration::from_millis(ms.parse()?)),
+            None => None,
+        },
+        history: matches.value_of("history").unwrap().parse()?,
+    };
+    let addr = matches.value_of("listen").unwrap().parse()?;
+    let inspect = match matches.value_of("inspect") {
+        Some(addr) => Some(addr.parse()?),
+        None => None,
+    };
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(run(addr, inspect, acceptor, Arc::new(api::State::new(config))))
+}
@@ -0,0 +1,64 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, PKeyRef, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{self, AlpnError, SslAcceptor, SslMethod, SslVerifyMode};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509Ref, X509};
+
+/// ALPN protocols offered by the server, in wire format.
+const ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
+/// Generate a self signed certificate for localhost.
+pub fn self_signed() -> Result<(X509, PKey<Private>), ErrorStack> {
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", "localhost")?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial = serial.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(365)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .ip("127.0.0.1")
+        .ip("::1")
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(san)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    Ok((builder.build(), key))
+}
+
+/// Build the TLS acceptor.
+///
+/// Clients are asked for a certificate, which is accepted without
+/// verification so any provider certificate can be used. Clients without a
+/// certificate must use token authentication.
+pub fn acceptor(cert: &X509Ref, key: &PKeyRef<Private>) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_certificate(cert)?;
+    builder.set_private_key(key)?;
+    builder.check_private_key()?;
+    builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+    // Required with client certificates to resume sessions on reconnect.
+    builder.set_session_id_context(b"apns2-emulator")?;
+    builder.set_alpn_select_callback(|_, client| {
+        ssl::select_next_proto(ALPN_PROTOCOLS, client).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder.build())
+}
@@ -0,0 +1,327 @@
+//! End-to-end tests of `ApnsSync` against the emulator.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use apns2::{
+    ApiErrorReason, ApnsSync, Auth, Environment, Notification, NotificationBuilder,
+    ProviderCertificate, ProviderToken, SendError,
+};
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::x509::{X509NameBuilder, X509};
+use serde_json::Value;
+
+#[path = "../../src/test_util.rs"]
+mod test_util;
+
+use test_util::generate_key;
+
+const DEVICE_TOKEN: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+/// An emulator process on a free port, killed when dropped.
+struct Emulator {
+    process: Child,
+    port: u16,
+    /// The server certificate, for clients to trust.
+    cert: PathBuf,
+    /// Notifications printed by the emulator.
+    pushes: Arc<Mutex<Vec<Value>>>,
+}
+
+impl Emulator {
+    fn start(args: &[&str]) -> Emulator {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .unwrap()
+            .port();
+        let cert = env::temp_dir().join(format!("apns2-emulator-{}.pem", port));
+        let mut process = Command::new(env!("CARGO_BIN_EXE_apns2-emulator"))
+            .arg("--listen")
+            .arg(format!("127.0.0.1:{}", port))
+            .arg("--write-cert")
+            .arg(&cert)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let pushes = Arc::new(Mutex::new(Vec::new()));
+        let stdout = process.stdout.take().unwrap();
+        let received = pushes.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                received.lock().unwrap().push(serde_json::from_str(&line).unwrap());
+            }
+        });
+
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(10), "Emulator did not start");
+            thread::sleep(Duration::from_millis(20));
+        }
+        Emulator {
+            process,
+            port,
+            cert,
+            pushes,
+        }
+    }
+
+    fn url(&self) -> String {
+        format!("https://localhost:{}", self.port)
+    }
+
+    /// A client sending to the emulator with token authentication.
+    fn client(&self, token: ProviderToken) -> ApnsSync {
+        self.client_with_auth(Auth::ProviderToken(token))
+    }
+
+    /// A client sending to the emulator.
+    fn client_with_auth(&self, auth: Auth) -> ApnsSync {
+        let mut apns = ApnsSync::new(auth).unwrap();
+        apns.set_endpoint(Some(self.url()));
+        apns.set_ca_certificate(&self.cert).unwrap();
+        apns
+    }
+
+    /// Wait until the emulator received `count` notifications, and return
+    /// them.
+    fn wait_for_pushes(&self, count: usize) -> Vec<Value> {
+        let started = Instant::now();
+        loop {
+            let pushes = self.pushes.lock().unwrap().clone();
+            if pushes.len() >= count {
+                return pushes;
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "Received {:?}",
+                pushes
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_file(&self.cert);
+    }
+}
+
+/// A provider token with a new signing key.
+fn provider_token(key_id: &str) -> ProviderToken {
+    ProviderToken::new(key_id, "TEAMID", &generate_key())
+}
+
+/// Write a self signed client certificate with the given common name, and
+/// its key, as PEM files.
+fn write_certificate(cert_path: &PathBuf, key_path: &PathBuf, common_name: &str) {
+    let key = PKey::private_key_from_pem(generate_key().as_bytes()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", common_name).unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(365).unwrap()).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+    fs::write(cert_path, cert.build().to_pem().unwrap()).unwrap();
+    fs::write(key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+}
+
+fn notification() -> Notification {
+    NotificationBuilder::new("com.example.app".into(), DEVICE_TOKEN.into())
+        .title("Hello")
+        .build()
+}
+
+/// The status (or reason) of each received notification.
+fn statuses(pushes: &[Value]) -> Vec<String> {
+    pushes
+        .iter()
+        .map(|push| match push["reason"].as_str() {
+            Some(reason) => reason.to_string(),
+            None => push["status"].to_string(),
+        })
+        .collect()
+}
+
+#[test]
+fn test_send() {
+    let emulator = Emulator::start(&[]);
+    let apns = emulator.client(provider_token("KEYID"));
+
+    let id = apns.send(notification()).unwrap();
+
+    let pushes = emulator.wait_for_pushes(1);
+    assert_eq!(pushes[0]["status"], 200);
+    assert_eq!(pushes[0]["apns_id"], id.to_string());
+    assert_eq!(pushes[0]["topic"], "com.example.app");
+    assert_eq!(pushes[0]["auth"], "token");
+    assert_eq!(pushes[0]["payload"]["aps"]["alert"]["title"], "Hello");
+}
+
+#[test]
+fn test_resend_after_goaway() {
+    let emulator = Emulator::start(&["--shutdown-after", "1"]);
+    let apns = emulator.client(provider_token("KEYID"));
+
+    apns.send(notification()).unwrap();
+    // Answered with Shutdown on the first connection, then resent on a new
+    // one.
+    apns.send(notification()).unwrap();
+    let pushes = emulator.wait_for_pushes(3);
+    assert_eq!(statuses(&pushes), ["200", "Shutdown", "200"]);
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let emulator = Emulator::start(&["--revoked-key", "REVOKED"]);
+    let mut apns = emulator.client(provider_token("REVOKED"));
+
+    match apns.send(notification()) {
+        Err(SendError::Api(ref e)) if matches!(e.reason, ApiErrorReason::InvalidProviderToken) => (),
+        other => panic!("Expected InvalidProviderToken, got {:?}", other),
+    }
+    assert!(matches!(
+        apns.circuit_open(),
+        Some(ApiErrorReason::InvalidProviderToken)
+    ));
+    // Rejected without contacting the server.
+    match apns.send(notification()) {
+        Err(SendError::CircuitOpen(_)) => (),
+        other => panic!("Expected an open circuit, got {:?}", other),
+    }
+
+    apns.reset_circuit();
+    assert!(apns.send(notification()).is_err());
+    assert!(apns.circuit_open().is_some());
+
+    apns.set_auth(Auth::ProviderToken(provider_token("KEYID"))).unwrap();
+    assert!(apns.circuit_open().is_none());
+    apns.send(notification()).unwrap();
+
+    let pushes = emulator.wait_for_pushes(3);
+    assert_eq!(
+        statuses(&pushes),
+        ["InvalidProviderToken", "InvalidProviderToken", "200"]
+    );
+}
+
+#[test]
+fn test_environment_fallback() {
+    let production = Emulator::start(&["--bad-device-token", DEVICE_TOKEN]);
+    let sandbox = Emulator::start(&[]);
+    // Trust the certificates of both emulators.
+    let ca = env::temp_dir().join(format!(
+        "apns2-emulator-{}-{}.pem",
+        production.port, sandbox.port
+    ));
+    let mut pem = fs::read(&production.cert).unwrap();
+    pem.extend(fs::read(&sandbox.cert).unwrap());
+    fs::write(&ca, pem).unwrap();
+
+    let mut apns = ApnsSync::with_token(provider_token("KEYID")).unwrap();
+    apns.set_ca_certificate(&ca).unwrap();
+    apns.set_production(true);
+    apns.set_environment_endpoint(Environment::Production, Some(production.url()));
+    apns.set_environment_endpoint(Environment::Sandbox, Some(sandbox.url()));
+    apns.set_environment_fallback(true);
+
+    let response = apns.send_detailed(notification());
+    fs::remove_file(&ca).unwrap();
+    assert_eq!(response.unwrap().environment, Environment::Sandbox);
+    assert_eq!(statuses(&production.wait_for_pushes(1)), ["BadDeviceToken"]);
+    assert_eq!(statuses(&sandbox.wait_for_pushes(1)), ["200"]);
+}
+
+#[test]
+fn test_send_certificate() {
+    let emulator = Emulator::start(&[]);
+    let cert = env::temp_dir().join(format!("apns2-client-{}.pem", emulator.port));
+    let key = env::temp_dir().join(format!("apns2-client-{}.key", emulator.port));
+    write_certificate(&cert, &key, "Separate");
+    let apns = emulator.client_with_auth(Auth::ProviderCertificate(
+        ProviderCertificate::pem_with_key(&cert, &key, None),
+    ));
+    apns.send(notification()).unwrap();
+
+    // The certificate followed by the key in a single file.
+    let bundle = env::temp_dir().join(format!("apns2-client-{}-bundle.pem", emulator.port));
+    write_certificate(&cert, &key, "Bundle");
+    let mut pem = fs::read(&cert).unwrap();
+    pem.extend(fs::read(&key).unwrap());
+    fs::write(&bundle, pem).unwrap();
+    let apns = emulator.client_with_auth(Auth::ProviderCertificate(ProviderCertificate::pem(
+        &bundle, None,
+    )));
+    apns.send(notification()).unwrap();
+    for path in &[cert, key, bundle] {
+        fs::remove_file(path).unwrap();
+    }
+
+    let pushes = emulator.wait_for_pushes(2);
+    assert_eq!(pushes[0]["status"], 200);
+    assert_eq!(pushes[0]["auth"], "certificate");
+    assert_eq!(pushes[0]["certificate"], "Separate");
+    assert_eq!(pushes[1]["status"], 200);
+    assert_eq!(pushes[1]["certificate"], "Bundle");
+}
+
+#[test]
+fn test_rotate_certificate() {
+    let emulator = Emulator::start(&[]);
+    let cert = env::temp_dir().join(format!("apns2-client-{}.pem", emulator.port));
+    let key = env::temp_dir().join(format!("apns2-client-{}.key", emulator.port));
+    write_certificate(&cert, &key, "ClientA");
+    let mut apns = emulator.client_with_auth(Auth::ProviderCertificate(
+        ProviderCertificate::pem_with_key(&cert, &key, None),
+    ));
+    apns.set_watch_credentials(true);
+    apns.send(notification()).unwrap();
+
+    write_certificate(&cert, &key, "ClientB");
+    // Make sure the change is noticed with a coarse timestamp resolution.
+    for path in &[&cert, &key] {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+    }
+    apns.send(notification()).unwrap();
+    apns.send(notification()).unwrap();
+
+    let other_cert = env::temp_dir().join(format!("apns2-client-{}-c.pem", emulator.port));
+    let other_key = env::temp_dir().join(format!("apns2-client-{}-c.key", emulator.port));
+    write_certificate(&other_cert, &other_key, "ClientC");
+    apns.set_auth(Auth::ProviderCertificate(ProviderCertificate::pem_with_key(
+        &other_cert,
+        &other_key,
+        None,
+    )))
+    .unwrap();
+    apns.send(notification()).unwrap();
+    for path in &[cert, key, other_cert, other_key] {
+        fs::remove_file(path).unwrap();
+    }
+
+    let pushes = emulator.wait_for_pushes(4);
+    let certificates: Vec<_> = pushes.iter().map(|p| p["certificate"].clone()).collect();
+    assert_eq!(certificates, ["ClientA", "ClientB", "ClientB", "ClientC"]);
+}
@@ -0,0 +1,387 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use curl::easy::Easy2;
+use openssl::bn::BigNumRef;
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::PKey;
+
+use error::CredentialError;
+
+/// Provider tokens are regenerated after this many seconds.
+///
+/// APNS rejects tokens older than one hour, and tokens that are refreshed
+/// more often than every 20 minutes (TooManyProviderTokenUpdates).
+const TOKEN_LIFETIME: u64 = 50 * 60;
+
+/// File format of a provider certificate.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CertificateFormat {
+    /// A PKCS#12 archive containing both certificate and private key.
+    P12,
+    /// A PEM file containing both certificate and private key.
+    PemBundle,
+    /// A PEM certificate with the private key in a separate PEM file.
+    PemCertKey { key_path: PathBuf },
+}
+
+/// Placeholder for a secret in `Debug` output.
+pub(crate) fn redacted(secret: &Option<String>) -> Option<&'static str> {
+    secret.as_ref().map(|_| "[redacted]")
+}
+
+/// A provider certificate + private key stored in files.
+#[derive(Clone)]
+pub struct ProviderCertificate {
+    /// Path to the certificate (or PKCS#12 archive).
+    pub path: PathBuf,
+    pub format: CertificateFormat,
+    /// Passphrase for the PKCS#12 archive or the private key.
+    pub passphrase: Option<String>,
+}
+
+impl fmt::Debug for ProviderCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProviderCertificate")
+            .field("path", &self.path)
+            .field("format", &self.format)
+            .field("passphrase", &redacted(&self.passphrase))
+            .finish()
+    }
+}
+
+impl ProviderCertificate {
+    /// A PKCS#12 archive containing both certificate and private key.
+    pub fn p12<P: AsRef<Path>>(path: P, passphrase: Option<String>) -> Self {
+        ProviderCertificate {
+            path: path.as_ref().to_path_buf(),
+            format: CertificateFormat::P12,
+            passphrase,
+        }
+    }
+
+    /// A PEM file containing both certificate and private key.
+    pub fn pem<P: AsRef<Path>>(path: P, passphrase: Option<String>) -> Self {
+        ProviderCertificate {
+            path: path.as_ref().to_path_buf(),
+            format: CertificateFormat::PemBundle,
+            passphrase,
+        }
+    }
+
+    /// A PEM certificate with a separate PEM private key file.
+    pub fn pem_with_key<P: AsRef<Path>, K: AsRef<Path>>(
+        cert_path: P,
+        key_path: K,
+        passphrase: Option<String>,
+    ) -> Self {
+        ProviderCertificate {
+            path: cert_path.as_ref().to_path_buf(),
+            format: CertificateFormat::PemCertKey {
+                key_path: key_path.as_ref().to_path_buf(),
+            },
+            passphrase,
+        }
+    }
+
+    /// Paths of all files making up the certificate.
+    fn paths(&self) -> Vec<&Path> {
+        match self.format {
+            CertificateFormat::PemCertKey { ref key_path } => vec![&self.path, key_path],
+            _ => vec![&self.path],
+        }
+    }
+}
+
+/// A provider certificate + private key held in memory.
+#[derive(Clone)]
+pub enum CertificateData {
+    /// A PKCS#12 archive containing both certificate and private key.
+    P12 {
+        data: Vec<u8>,
+        passphrase: Option<String>,
+    },
+    /// A PEM encoded certificate with a separate PEM encoded private key.
+    Pem {
+        cert: Vec<u8>,
+        key: Vec<u8>,
+        passphrase: Option<String>,
+    },
+}
+
+impl fmt::Debug for CertificateData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CertificateData::P12 { ref data, .. } => {
+                write!(f, "CertificateData::P12({} bytes)", data.len())
+            }
+            CertificateData::Pem { ref cert, .. } => {
+                write!(f, "CertificateData::Pem({} bytes)", cert.len())
+            }
+        }
+    }
+}
+
+/// A token signing key (.p8) for token based authentication.
+#[derive(Clone)]
+pub struct ProviderToken {
+    /// The 10 character key id of the signing key.
+    pub key_id: String,
+    /// The 10 character team id of the developer account.
+    pub team_id: String,
+    /// The PEM encoded PKCS#8 private key, as contained in the .p8 file.
+    pub key: Vec<u8>,
+    /// The .p8 file the key was read from, which is read again when it is
+    /// modified if `ApnsSync::set_watch_credentials` is enabled.
+    pub path: Option<PathBuf>,
+}
+
+impl fmt::Debug for ProviderToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProviderToken")
+            .field("key_id", &self.key_id)
+            .field("team_id", &self.team_id)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl ProviderToken {
+    /// Create a provider token from the PEM encoded key.
+    pub fn new<S: Into<String>>(key_id: S, team_id: S, key: &str) -> Self {
+        ProviderToken {
+            key_id: key_id.into(),
+            team_id: team_id.into(),
+            key: key.as_bytes().to_vec(),
+            path: None,
+        }
+    }
+
+    /// Read the key from a .p8 file.
+    pub fn from_file<S: Into<String>, P: AsRef<Path>>(
+        key_id: S,
+        team_id: S,
+        path: P,
+    ) -> Result<Self, CredentialError> {
+        let path = path.as_ref();
+        let mut token = Self::new(key_id, team_id, "");
+        token.key = read_key(path)?;
+        token.path = Some(path.to_path_buf());
+        Ok(token)
+    }
+
+    /// Read the PEM encoded key from an environment variable.
+    ///
+    /// Escaped newlines (`\n`) are replaced with actual line breaks, since
+    /// multi line values are often flattened that way.
+    pub fn from_env<S: Into<String>>(
+        key_id: S,
+        team_id: S,
+        var: &str,
+    ) -> Result<Self, CredentialError> {
+        let key = env::var(var).map_err(|source| CredentialError::Env {
+            var: var.to_string(),
+            source,
+        })?;
+        Ok(Self::new(key_id, team_id, &key.replace("\\n", "\n")))
+    }
+
+    /// Generate a signed JWT, issued at the given UNIX timestamp.
+    fn sign(&self, issued_at: u64) -> Result<String, ErrorStack> {
+        let header = json!({ "alg": "ES256", "kid": self.key_id });
+        let claims = json!({ "iss": self.team_id, "iat": issued_at });
+        let input = format!(
+            "{}.{}",
+            base64_url(header.to_string().as_bytes()),
+            base64_url(claims.to_string().as_bytes())
+        );
+
+        let key = PKey::private_key_from_pem(&self.key)?.ec_key()?;
+        let digest = hash(MessageDigest::sha256(), input.as_bytes())?;
+        let sig = EcdsaSig::sign(&digest, &key)?;
+
+        // JWS uses the raw r || s encoding rather than DER.
+        let mut raw = padded(sig.r())?;
+        raw.extend(padded(sig.s())?);
+
+        Ok(format!("{}.{}", input, base64_url(&raw)))
+    }
+}
+
+fn read_key(path: &Path) -> Result<Vec<u8>, CredentialError> {
+    fs::read(path).map_err(|source| CredentialError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn base64_url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn padded(n: &BigNumRef) -> Result<Vec<u8>, ErrorStack> {
+    n.to_vec_padded(32)
+}
+
+/// Caches the signed provider token until it needs to be refreshed.
+#[derive(Default)]
+pub(crate) struct TokenCache {
+    token: Option<(String, u64)>,
+}
+
+impl TokenCache {
+    /// Get a valid token, signing a new one if required.
+    pub fn get(&mut self, key: &ProviderToken) -> Result<String, CredentialError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match self.token {
+            Some((ref token, issued_at)) if now < issued_at + TOKEN_LIFETIME => {
+                return Ok(token.clone())
+            }
+            _ => {}
+        }
+        let token = key.sign(now)?;
+        self.token = Some((token.clone(), now));
+        Ok(token)
+    }
+
+    /// Discard the cached token.
+    pub fn clear(&mut self) {
+        self.token = None;
+    }
+}
+
+/// Credentials used to authenticate with the APNS api.
+#[derive(Clone, Debug)]
+pub enum Auth {
+    ProviderCertificate(ProviderCertificate),
+    CertificateData(CertificateData),
+    ProviderToken(ProviderToken),
+}
+
+impl Auth {
+    /// Configure the curl handle to authenticate with these credentials.
+    ///
+    /// Token authentication is handled per request with an `authorization`
+    /// header, so no TLS configuration is required.
+    pub(crate) fn configure<H>(&self, easy: &mut Easy2<H>) -> Result<(), ::curl::Error> {
+        match *self {
+            Auth::ProviderCertificate(ref cert) => {
+                easy.ssl_cert(&cert.path)?;
+                match cert.format {
+                    CertificateFormat::P12 => {
+                        easy.ssl_cert_type("P12")?;
+                    }
+                    CertificateFormat::PemBundle => {
+                        easy.ssl_cert_type("PEM")?;
+                        easy.ssl_key(&cert.path)?;
+                        easy.ssl_key_type("PEM")?;
+                    }
+                    CertificateFormat::PemCertKey { ref key_path } => {
+                        easy.ssl_cert_type("PEM")?;
+                        easy.ssl_key(key_path)?;
+                        easy.ssl_key_type("PEM")?;
+                    }
+                }
+                if let Some(ref pw) = cert.passphrase {
+                    easy.key_password(pw)?;
+                }
+            }
+            Auth::CertificateData(CertificateData::P12 {
+                ref data,
+                ref passphrase,
+            }) => {
+                easy.ssl_cert_blob(data)?;
+                easy.ssl_cert_type("P12")?;
+                if let Some(ref pw) = *passphrase {
+                    easy.key_password(pw)?;
+                }
+            }
+            Auth::CertificateData(CertificateData::Pem {
+                ref cert,
+                ref key,
+                ref passphrase,
+            }) => {
+                easy.ssl_cert_blob(cert)?;
+                easy.ssl_cert_type("PEM")?;
+                easy.ssl_key_blob(key)?;
+                easy.ssl_key_type("PEM")?;
+                if let Some(ref pw) = *passphrase {
+                    easy.key_password(pw)?;
+                }
+            }
+            Auth::ProviderToken(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Paths of the credential files.
+    fn paths(&self) -> Vec<&Path> {
+        match *self {
+            Auth::ProviderCertificate(ref cert) => cert.paths(),
+            Auth::ProviderToken(ProviderToken {
+                path: Some(ref path),
+                ..
+            }) => vec![path],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns true if the credentials are read from files, which can be
+    /// watched for changes.
+    pub(crate) fn is_watchable(&self) -> bool {
+        !self.paths().is_empty()
+    }
+
+    /// Latest modification time of the credential files.
+    ///
+    /// Returns `None` for credentials that are not read from a file.
+    pub(crate) fn modified(&self) -> Option<SystemTime> {
+        self.paths()
+            .into_iter()
+            .filter_map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+            .max()
+    }
+
+    /// Read the token key again from its file.
+    ///
+    /// Certificate files are read by curl for each new connection.
+    pub(crate) fn reload(&mut self) -> Result<(), CredentialError> {
+        if let Auth::ProviderToken(ref mut token) = *self {
+            if let Some(ref path) = token.path {
+                token.key = read_key(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::generate_key;
+
+    #[test]
+    fn test_sign_token() {
+        let pem = generate_key();
+
+        let token = ProviderToken::new("KEYID", "TEAMID", &pem);
+        let jwt = token.sign(1_500_000_000).unwrap();
+
+        let parts: Vec<_> = jwt.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let header = base64::decode_config(parts[0], base64::URL_SAFE_NO_PAD).unwrap();
+        let header: ::serde_json::Value = ::serde_json::from_slice(&header).unwrap();
+        assert_eq!(header["kid"], "KEYID");
+        let sig = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).unwrap();
+        assert_eq!(sig.len(), 64);
+    }
+}
@@ -0,0 +1,259 @@
+//! HTTP gateway for sending notifications from other services.
+//!
+//! Requires the `gateway` feature.
+//!
+//! The gateway holds the provider credentials and forwards notifications
+//! through a pool of clients. Callers authenticate with an API key in the
+//! `Authorization: Bearer <key>` header.
+//!
+//! * `POST /v1/send`: send a single serialized `Notification`.
+//! * `POST /v1/batch`: send a JSON array of notifications.
+//! * `POST /v1/reset-circuit`: close the circuit breakers after fixing the
+//!   credentials.
+//! * `GET /health`: returns 200 while the gateway is running, with the reason
+//!   of an open circuit breaker in `circuit_open`.
+
+extern crate apns2;
+extern crate clap;
+#[macro_use]
+extern crate serde_json;
+extern crate tiny_http;
+
+mod common;
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use apns2::{ApnsPool, BoxError, Notification};
+use clap::{App, Arg, ArgMatches};
+use serde_json::Value;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use common::{client, credential_args, report, send_result};
+
+/// Maximum accepted request body size.
+const MAX_BODY_SIZE: u64 = 10 * 1024 * 1024;
+/// Maximum number of notifications in a batch request.
+const MAX_BATCH_SIZE: usize = 5000;
+
+struct Gateway {
+    pool: Arc<ApnsPool>,
+    /// API key to caller name.
+    api_keys: HashMap<String, String>,
+}
+
+/// HTTP status for the result of a single send.
+fn result_status(result: &Value) -> u16 {
+    match result["status"].as_u64() {
+        Some(status) => status as u16,
+        None => 502,
+    }
+}
+
+fn json_response(status: u16, body: &Value) -> Response<::std::io::Cursor<Vec<u8>>> {
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("Valid header");
+    Response::from_data(body.to_string().into_bytes())
+        .with_status_code(status)
+        .with_header(header)
+}
+
+fn error_response(status: u16, message: &str) -> Response<::std::io::Cursor<Vec<u8>>> {
+    json_response(status, &json!({ "error": message }))
+}
+
+impl Gateway {
+    /// Look up the caller by the API key of the request.
+    fn caller(&self, request: &Request) -> Option<&str> {
+        let auth = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))?;
+        let key = auth.value.as_str().strip_prefix("Bearer ")?;
+        self.api_keys.get(key.trim()).map(|name| name.as_str())
+    }
+
+    fn send(&self, body: &str) -> (u16, Value) {
+        let notification: Notification = match serde_json::from_str(body) {
+            Ok(n) => n,
+            Err(e) => return (400, json!({ "error": format!("Invalid notification: {}", e) })),
+        };
+        let device_token = notification.device_token.clone();
+        let result = send_result(&device_token, self.pool.send_detailed(notification));
+        (result_status(&result), result)
+    }
+
+    fn batch(&self, body: &str) -> (u16, Value) {
+        let notifications: Vec<Value> = match serde_json::from_str(body) {
+            Ok(n) => n,
+            Err(e) => return (400, json!({ "error": format!("Invalid batch: {}", e) })),
+        };
+        if notifications.len() > MAX_BATCH_SIZE {
+            let message = format!("Batch exceeds {} notifications", MAX_BATCH_SIZE);
+            return (413, json!({ "error": message }));
+        }
+
+        // Invalid entries are reported individually, like failed sends.
+        let results = notifications
+            .into_iter()
+            .map(|value| match serde_json::from_value::<Notification>(value) {
+                Ok(n) => {
+                    let device_token = n.device_token.clone();
+                    send_result(&device_token, self.pool.send_detailed(n))
+                }
+                Err(e) => json!({
+                    "status": null,
+                    "error": format!("Invalid notification: {}", e),
+                }),
+            })
+            .collect();
+        (200, json!({ "results": Value::Array(results) }))
+    }
+
+    fn handle(&self, mut request: Request) {
+        let method = request.method().clone();
+        let path = request.url().split('?').next().unwrap_or("").to_string();
+
+        if method == Method::Get && path == "/health" {
+            let health = json!({ "status": "ok", "circuit_open": self.pool.circuit_open() });
+            let _ = request.respond(json_response(200, &health));
+            return;
+        }
+        let caller = match self.caller(&request) {
+            Some(caller) => caller.to_string(),
+            None => {
+                let _ = request.respond(error_response(401, "Missing or unknown API key"));
+                return;
+            }
+        };
+
+        let mut body = String::new();
+        let read = request
+            .as_reader()
+            .take(MAX_BODY_SIZE + 1)
+            .read_to_string(&mut body);
+        let (status, response) = match (method, path.as_str()) {
+            _ if read.is_err() => (400, json!({ "error": "Could not read request body" })),
+            _ if body.len() as u64 > MAX_BODY_SIZE => {
+                (413, json!({ "error": "Request body too large" }))
+            }
+            (Method::Post, "/v1/send") => self.send(&body),
+            (Method::Post, "/v1/batch") => self.batch(&body),
+            (Method::Post, "/v1/reset-circuit") => {
+                self.pool.reset_circuit();
+                (200, json!({ "status": "ok" }))
+            }
+            (_, "/v1/send") | (_, "/v1/batch") | (_, "/v1/reset-circuit") => {
+                (405, json!({ "error": "Method not allowed" }))
+            }
+            _ => (404, json!({ "error": "Not found" })),
+        };
+        eprintln!("{} {} {} {}", caller, request.method(), path, status);
+        let _ = request.respond(json_response(status, &response));
+    }
+}
+
+/// Parse `NAME=KEY` API key arguments.
+fn parse_api_keys(args: &ArgMatches) -> Result<HashMap<String, String>, BoxError> {
+    let mut keys = HashMap::new();
+    for value in args.values_of("api-key").into_iter().flatten() {
+        let mut parts = value.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(key)) if !name.is_empty() && !key.is_empty() => {
+                keys.insert(key.to_string(), name.to_string());
+            }
+            _ => {
+                return Err(format!("Invalid API key {:?}, expected NAME=KEY", value).into())
+            }
+        }
+    }
+    Ok(keys)
+}
+
+fn run(args: &ArgMatches) -> Result<(), BoxError> {
+    let connections: usize = args.value_of("connections").unwrap().parse()?;
+    let workers: usize = args.value_of("workers").unwrap().parse()?;
+    let gateway = Arc::new(Gateway {
+        pool: Arc::new(ApnsPool::new(
+            (0..connections.max(1))
+                .map(|_| client(args))
+                .collect::<Result<_, _>>()?,
+        )),
+        api_keys: parse_api_keys(args)?,
+    });
+    // Rotated credentials are picked up and close the circuit breakers.
+    gateway.pool.set_watch_credentials(true);
+    if let Err(e) = gateway.pool.connect() {
+        eprintln!("Warning: could not connect to APNS: {}", report(&e));
+    }
+    ApnsPool::spawn_health_check(&gateway.pool, Duration::from_secs(15));
+
+    let listen = args.value_of("listen").unwrap();
+    let server = Arc::new(Server::http(listen)?);
+    eprintln!("apns2-gateway listening on http://{}", listen);
+
+    let handles: Vec<_> = (0..workers.max(1))
+        .map(|_| {
+            let server = server.clone();
+            let gateway = gateway.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    gateway.handle(request);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("Worker thread panicked");
+    }
+    Ok(())
+}
+
+fn main() {
+    let app = App::new("apns2-gateway")
+        .about("HTTP gateway for sending notifications with the Apple Push Notification service")
+        .args(&credential_args())
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .env("APNS_GATEWAY_LISTEN")
+                .default_value("127.0.0.1:8000")
+                .help("Address to listen on"),
+        )
+        .arg(
+            Arg::with_name("api-key")
+                .long("api-key")
+                .value_name("NAME=KEY")
+                .env("APNS_GATEWAY_API_KEYS")
+                .hide_env_values(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .required(true)
+                .help("API key of a caller, comma separated in the environment variable"),
+        )
+        .arg(
+            Arg::with_name("connections")
+                .long("connections")
+                .value_name("N")
+                .default_value("4")
+                .help("Number of pooled APNS connections"),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .value_name("N")
+                .default_value("8")
+                .help("Number of threads handling HTTP requests"),
+        );
+
+    if let Err(e) = run(&app.get_matches()) {
+        eprintln!("Error: {}", report(&*e));
+        process::exit(1);
+    }
+}
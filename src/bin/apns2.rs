@@ -0,0 +1,322 @@
+//! Command line tool for sending notifications.
+//!
+//! Requires the `cli` feature.
+
+extern crate apns2;
+extern crate clap;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use apns2::{ApnsSync, BoxError, Notification, NotificationBuilder, Priority, PushType};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use common::{client, credential_args, report};
+
+const PUSH_TYPES: &[&str] = &[
+    "alert",
+    "background",
+    "location",
+    "voip",
+    "complication",
+    "fileprovider",
+    "mdm",
+    "liveactivity",
+    "pushtotalk",
+];
+
+fn parse_push_type(value: &str) -> Result<PushType, BoxError> {
+    Ok(serde_json::from_value(value.into())?)
+}
+
+fn parse_priority(value: &str) -> Option<Priority> {
+    match value {
+        "5" => Some(Priority::Low),
+        "10" => Some(Priority::High),
+        _ => None,
+    }
+}
+
+fn send(args: &ArgMatches) -> Result<(), BoxError> {
+    let apns = client(args)?;
+
+    let mut builder = NotificationBuilder::for_device(args.value_of("device-token").unwrap().into());
+    if let Some(path) = args.value_of("payload") {
+        builder = builder.raw_payload(&fs::read_to_string(path)?)?;
+    }
+    if let Some(title) = args.value_of("title") {
+        builder = builder.title(title);
+    }
+    if let Some(body) = args.value_of("body") {
+        builder = builder.body(body);
+    }
+    if let Some(badge) = args.value_of("badge") {
+        builder = builder.badge(badge.parse()?);
+    }
+    if let Some(sound) = args.value_of("sound") {
+        builder = builder.sound(sound);
+    }
+    if let Some(push_type) = args.value_of("push-type") {
+        builder = builder.push_type(parse_push_type(push_type)?);
+    }
+    if args.is_present("voip") {
+        builder = builder.voip();
+    }
+    if args.is_present("background") {
+        builder = builder.background();
+    }
+    if let Some(priority) = args.value_of("priority").and_then(parse_priority) {
+        builder = builder.priority(priority);
+    }
+
+    match apns.send(builder.build()) {
+        Ok(id) => {
+            println!("{}", id);
+            Ok(())
+        }
+        Err(e) => {
+            match e.as_api_error() {
+                Some(api) => eprintln!("{} (status {})", api.reason, api.status),
+                None => eprintln!("{}", report(&e)),
+            }
+            process::exit(1);
+        }
+    }
+}
+
+/// Send a single line of a batch file and build the report entry.
+fn send_line(apns: &ApnsSync, line_number: usize, line: &str) -> serde_json::Value {
+    let notification: Notification = match serde_json::from_str(line) {
+        Ok(n) => n,
+        Err(e) => {
+            return json!({
+                "line": line_number,
+                "status": null,
+                "error": format!("Invalid notification: {}", e),
+            })
+        }
+    };
+    let device_token = notification.device_token.clone();
+
+    let mut entry = common::send_result(&device_token, apns.send_detailed(notification));
+    entry["line"] = line_number.into();
+    entry
+}
+
+fn batch(args: &ArgMatches) -> Result<(), BoxError> {
+    let input: Box<dyn BufRead + Send> = match args.value_of("input") {
+        None | Some("-") => Box::new(BufReader::new(io::stdin())),
+        Some(path) => Box::new(BufReader::new(fs::File::open(path)?)),
+    };
+    let mut report: Box<dyn Write> = match args.value_of("report") {
+        None | Some("-") => Box::new(io::stdout()),
+        Some(path) => Box::new(BufWriter::new(fs::File::create(path)?)),
+    };
+    let concurrency: usize = args.value_of("concurrency").unwrap().parse()?;
+
+    let lines = Arc::new(Mutex::new(input.lines().enumerate()));
+    let (sender, receiver) = mpsc::channel();
+
+    // Each worker uses its own client, and with that its own connection.
+    let mut workers = Vec::new();
+    for _ in 0..concurrency.max(1) {
+        let apns = client(args)?;
+        let lines = lines.clone();
+        let sender = sender.clone();
+        workers.push(thread::spawn(move || loop {
+            let next = lines.lock().unwrap().next();
+            let (index, line) = match next {
+                Some((index, Ok(line))) => (index, line),
+                Some((index, Err(e))) => {
+                    eprintln!("Could not read line {}: {}", index + 1, e);
+                    break;
+                }
+                None => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if sender.send(send_line(&apns, index + 1, &line)).is_err() {
+                break;
+            }
+        }));
+    }
+    drop(sender);
+
+    for entry in receiver {
+        writeln!(report, "{}", entry)?;
+    }
+    report.flush()?;
+    for worker in workers {
+        worker.join().expect("Worker thread panicked");
+    }
+    Ok(())
+}
+
+/// Validate a notification or raw payload file and print the issues.
+fn validate(args: &ArgMatches) -> Result<(), BoxError> {
+    let data = fs::read_to_string(args.value_of("file").unwrap())?;
+    let value: serde_json::Value = serde_json::from_str(&data)?;
+
+    // Raw payloads contain the aps dictionary at the top level.
+    let issues = if value.get("aps").is_some() {
+        let push_type = match args.value_of("push-type") {
+            Some(t) => Some(parse_push_type(t)?),
+            None => None,
+        };
+        let priority = args.value_of("priority").and_then(parse_priority);
+        apns2::validate_payload(&value, push_type, priority)
+    } else {
+        let notification: Notification = serde_json::from_value(value)?;
+        apns2::validate_notification(&notification)
+    };
+
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    if issues.iter().any(|i| i.is_error()) {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn main() {
+    let app = App::new("apns2")
+        .about("Send notifications with the Apple Push Notification service")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("Send a single notification")
+                .args(&credential_args())
+                .arg(
+                    Arg::with_name("device-token")
+                        .value_name("DEVICE_TOKEN")
+                        .required(true)
+                        .help("Device token to send to"),
+                )
+                .arg(
+                    Arg::with_name("payload")
+                        .long("payload")
+                        .value_name("FILE")
+                        .help("Raw JSON payload (with aps dictionary)"),
+                )
+                .arg(
+                    Arg::with_name("title")
+                        .long("title")
+                        .value_name("TITLE")
+                        .help("Alert title"),
+                )
+                .arg(
+                    Arg::with_name("body")
+                        .long("body")
+                        .value_name("BODY")
+                        .help("Alert body"),
+                )
+                .arg(
+                    Arg::with_name("badge")
+                        .long("badge")
+                        .value_name("NUMBER")
+                        .help("Badge number"),
+                )
+                .arg(
+                    Arg::with_name("sound")
+                        .long("sound")
+                        .value_name("SOUND")
+                        .help("Sound to play"),
+                )
+                .arg(
+                    Arg::with_name("push-type")
+                        .long("push-type")
+                        .value_name("TYPE")
+                        .help("Push type (apns-push-type header)")
+                        .possible_values(PUSH_TYPES),
+                )
+                .arg(
+                    Arg::with_name("voip")
+                        .long("voip")
+                        .conflicts_with("push-type")
+                        .help("Send a PushKit VoIP notification to the .voip topic"),
+                )
+                .arg(
+                    Arg::with_name("background")
+                        .long("background")
+                        .conflicts_with_all(&["push-type", "voip", "title", "body", "badge", "sound"])
+                        .help("Send a background notification with content-available"),
+                )
+                .arg(
+                    Arg::with_name("priority")
+                        .long("priority")
+                        .value_name("PRIORITY")
+                        .help("Priority (10: immediate, 5: power considerate)")
+                        .possible_values(&["5", "10"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Send notifications from a JSON Lines file")
+                .args(&credential_args())
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("FILE")
+                        .help("One JSON notification per line (default: stdin)"),
+                )
+                .arg(
+                    Arg::with_name("report")
+                        .long("report")
+                        .value_name("FILE")
+                        .help("Write the JSON Lines report to a file (default: stdout)"),
+                )
+                .arg(
+                    Arg::with_name("concurrency")
+                        .long("concurrency")
+                        .short("c")
+                        .value_name("N")
+                        .default_value("4")
+                        .help("Number of parallel connections"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check a notification or raw payload without sending it")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Serialized notification or raw JSON payload"),
+                )
+                .arg(
+                    Arg::with_name("push-type")
+                        .long("push-type")
+                        .value_name("TYPE")
+                        .help("Push type of a raw payload")
+                        .possible_values(PUSH_TYPES),
+                )
+                .arg(
+                    Arg::with_name("priority")
+                        .long("priority")
+                        .value_name("PRIORITY")
+                        .help("Priority of a raw payload")
+                        .possible_values(&["5", "10"]),
+                ),
+        );
+
+    let matches = app.get_matches();
+    let result = match matches.subcommand() {
+        ("send", Some(args)) => send(args),
+        ("batch", Some(args)) => batch(args),
+        ("validate", Some(args)) => validate(args),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", report(&*e));
+        process::exit(1);
+    }
+}
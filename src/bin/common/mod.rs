@@ -0,0 +1,157 @@
+//! Helpers shared by the command line tool and the gateway.
+
+use std::error::Error;
+
+use apns2::{ApnsSync, Auth, BoxError, ClientOptions, ProviderCertificate, ProviderToken, Proxy,
+            SendError, SendResponse};
+use clap::{Arg, ArgMatches};
+
+pub fn credential_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("cert")
+            .long("cert")
+            .value_name("PATH")
+            .help("Provider certificate (PKCS#12 or PEM)")
+            .env("APNS_CERT_PATH")
+            .required_unless("token-key"),
+        Arg::with_name("cert-format")
+            .long("cert-format")
+            .value_name("FORMAT")
+            .possible_values(&["p12", "pem"])
+            .help("Format of the certificate (default: p12, or pem with --key)"),
+        Arg::with_name("key")
+            .long("key")
+            .value_name("PATH")
+            .help("Separate PEM private key for the certificate"),
+        Arg::with_name("passphrase")
+            .long("passphrase")
+            .value_name("PASSPHRASE")
+            .env("APNS_CERT_PW")
+            .hide_env_values(true)
+            .help("Passphrase of the certificate or private key"),
+        Arg::with_name("token-key")
+            .long("token-key")
+            .value_name("PATH")
+            .help("Token signing key (.p8) for token authentication")
+            .conflicts_with("cert")
+            .requires_all(&["key-id", "team-id"]),
+        Arg::with_name("key-id")
+            .long("key-id")
+            .value_name("ID")
+            .help("Key id of the token signing key"),
+        Arg::with_name("team-id")
+            .long("team-id")
+            .value_name("ID")
+            .help("Team id for token authentication"),
+        Arg::with_name("sandbox")
+            .long("sandbox")
+            .help("Use the development sandbox instead of production"),
+        Arg::with_name("environment-fallback")
+            .long("environment-fallback")
+            .help("Retry in the other environment if the device token is rejected"),
+        Arg::with_name("endpoint")
+            .long("endpoint")
+            .value_name("URL")
+            .help("Custom API endpoint, e.g. a local emulator"),
+        Arg::with_name("ca-cert")
+            .long("ca-cert")
+            .value_name("PATH")
+            .help("PEM CA certificate to trust for the endpoint"),
+        Arg::with_name("proxy")
+            .long("proxy")
+            .value_name("URL")
+            .env("APNS_PROXY")
+            .help("HTTP, HTTPS or SOCKS proxy, may contain credentials"),
+        Arg::with_name("no-proxy")
+            .long("no-proxy")
+            .value_name("HOSTS")
+            .env("APNS_NO_PROXY")
+            .requires("proxy")
+            .help("Comma separated hosts to connect to without the proxy"),
+        Arg::with_name("topic")
+            .long("topic")
+            .value_name("TOPIC")
+            .env("APNS_TOPIC")
+            .help("Topic (bundle id), inferred from the certificate if omitted"),
+    ]
+}
+
+/// Build the client from the credential arguments.
+pub fn client(args: &ArgMatches) -> Result<ApnsSync, BoxError> {
+    let passphrase = args.value_of("passphrase").map(|p| p.to_string());
+    let auth = if let Some(path) = args.value_of("token-key") {
+        Auth::ProviderToken(ProviderToken::from_file(
+            args.value_of("key-id").unwrap(),
+            args.value_of("team-id").unwrap(),
+            path,
+        )?)
+    } else {
+        let path = args.value_of("cert").unwrap();
+        let cert = match (args.value_of("cert-format"), args.value_of("key")) {
+            (Some("p12"), Some(_)) => return Err("--key requires --cert-format pem".into()),
+            (_, Some(key)) => ProviderCertificate::pem_with_key(path, key, passphrase),
+            (Some("pem"), None) => ProviderCertificate::pem(path, passphrase),
+            _ => ProviderCertificate::p12(path, passphrase),
+        };
+        Auth::ProviderCertificate(cert)
+    };
+
+    let mut apns = ApnsSync::new(auth)?;
+    apns.set_production(!args.is_present("sandbox"));
+    apns.set_environment_fallback(args.is_present("environment-fallback"));
+    apns.set_endpoint(args.value_of("endpoint").map(|e| e.to_string()));
+    if let Some(path) = args.value_of("ca-cert") {
+        apns.set_ca_certificate(path)?;
+    }
+    apns.set_default_topic(args.value_of("topic").map(|t| t.to_string()));
+    if let Some(url) = args.value_of("proxy") {
+        let proxy = args
+            .value_of("no-proxy")
+            .into_iter()
+            .flat_map(|hosts| hosts.split(','))
+            .fold(Proxy::new(url), |proxy, host| proxy.no_proxy(host.trim()));
+        apns.set_options(ClientOptions::new().proxy(proxy))?;
+    }
+    Ok(apns)
+}
+
+/// Describe the outcome of a send as JSON.
+///
+/// Successful sends contain the apns-id and environment, API errors the status and reason,
+/// and all other errors a description with a `null` status.
+pub fn send_result(
+    device_token: &str,
+    result: Result<SendResponse, SendError>,
+) -> serde_json::Value {
+    match result {
+        Ok(response) => json!({
+            "device_token": device_token,
+            "apns_id": response.apns_id,
+            "environment": response.environment,
+            "status": 200,
+        }),
+        Err(e) => match e.as_api_error() {
+            Some(api) => json!({
+                "device_token": device_token,
+                "status": api.status,
+                "reason": api.reason.to_string(),
+            }),
+            None => json!({
+                "device_token": device_token,
+                "status": null,
+                "error": report(&e),
+            }),
+        },
+    }
+}
+
+/// Describe an error and its sources, separated by colons.
+pub fn report(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message.push_str(&format!(": {}", e));
+        source = e.source();
+    }
+    message
+}
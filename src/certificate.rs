@@ -0,0 +1,303 @@
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openssl::asn1::Asn1Time;
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::x509::{X509, X509Ref};
+
+use auth::{Auth, CertificateData, CertificateFormat};
+use error::CredentialError;
+
+/// DER encoded prefix of Apple's push certificate extension OIDs
+/// (1.2.840.113635.100.6.3.x).
+const APPLE_PUSH_OID_PREFIX: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x63, 0x64, 0x06, 0x03];
+/// Extension marking a certificate as valid for the development sandbox.
+const OID_SANDBOX: u8 = 0x01;
+/// Extension marking a certificate as valid for production.
+const OID_PRODUCTION: u8 = 0x02;
+/// Extension listing the topics a certificate is valid for.
+const OID_TOPICS: u8 = 0x06;
+
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTF8_STRING: u8 = 0x0C;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_EXTENSIONS: u8 = 0xA3;
+
+/// The APNS environment(s) a provider certificate can be used with.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CertificateEnvironment {
+    Sandbox,
+    Production,
+    /// Valid for both sandbox and production.
+    Universal,
+}
+
+/// How the client reacts to problems with the provider certificate that are
+/// detected before sending a notification.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CertificatePolicy {
+    /// Do not check the certificate.
+    Ignore,
+    /// Log a warning, but send the notification anyway.
+    Warn,
+    /// Fail with `SendError::Certificate`.
+    Refuse,
+}
+
+/// Information extracted from a provider certificate.
+#[derive(Clone, Debug)]
+pub struct CertificateInfo {
+    /// Common name of the certificate subject.
+    pub subject: String,
+    /// The subject UID, which is the bundle id of the app.
+    pub uid: Option<String>,
+    /// Expiry date of the certificate.
+    pub not_after: SystemTime,
+    /// Environment the certificate is valid for, if it contains Apple's
+    /// environment extensions.
+    pub environment: Option<CertificateEnvironment>,
+    /// Topics the certificate is valid for.
+    /// Only present for certificates with Apple's topic extension.
+    pub topics: Vec<String>,
+}
+
+impl CertificateInfo {
+    /// Extract the information from a parsed certificate.
+    pub fn from_x509(cert: &X509Ref) -> Result<Self, ErrorStack> {
+        let entry = |nid| {
+            cert.subject_name()
+                .entries_by_nid(nid)
+                .next()
+                .and_then(|e| e.data().to_string().ok())
+        };
+
+        let diff = Asn1Time::from_unix(0)?.diff(cert.not_after())?;
+        let not_after =
+            UNIX_EPOCH + Duration::from_secs(diff.days as u64 * 86_400 + diff.secs as u64);
+
+        let mut sandbox = false;
+        let mut production = false;
+        let mut topics = Vec::new();
+        for (oid, value) in extensions(&cert.to_der()?) {
+            if oid.len() != APPLE_PUSH_OID_PREFIX.len() + 1
+                || !oid.starts_with(APPLE_PUSH_OID_PREFIX)
+            {
+                continue;
+            }
+            match oid[oid.len() - 1] {
+                OID_SANDBOX => sandbox = true,
+                OID_PRODUCTION => production = true,
+                OID_TOPICS => topics = parse_topics(value),
+                _ => {}
+            }
+        }
+        let environment = match (sandbox, production) {
+            (true, true) => Some(CertificateEnvironment::Universal),
+            (true, false) => Some(CertificateEnvironment::Sandbox),
+            (false, true) => Some(CertificateEnvironment::Production),
+            (false, false) => None,
+        };
+
+        Ok(CertificateInfo {
+            subject: entry(Nid::COMMONNAME).unwrap_or_default(),
+            uid: entry(Nid::USERID),
+            not_after,
+            environment,
+            topics,
+        })
+    }
+
+    /// Load the certificate used by the given credentials.
+    ///
+    /// Returns `None` for token based authentication.
+    pub fn from_auth(auth: &Auth) -> Result<Option<Self>, CredentialError> {
+        let cert = match *auth {
+            Auth::ProviderCertificate(ref c) => {
+                let data = fs::read(&c.path).map_err(|source| CredentialError::Read {
+                    path: c.path.clone(),
+                    source,
+                })?;
+                match c.format {
+                    CertificateFormat::P12 => parse_p12(&data, c.passphrase.as_ref())?,
+                    _ => X509::from_pem(&data)?,
+                }
+            }
+            Auth::CertificateData(CertificateData::P12 {
+                ref data,
+                ref passphrase,
+            }) => parse_p12(data, passphrase.as_ref())?,
+            Auth::CertificateData(CertificateData::Pem { ref cert, .. }) => X509::from_pem(cert)?,
+            Auth::ProviderToken(_) => return Ok(None),
+        };
+        Ok(Some(Self::from_x509(&cert)?))
+    }
+
+    /// Remaining validity of the certificate.
+    /// Returns `None` if the certificate already expired.
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.not_after.duration_since(SystemTime::now()).ok()
+    }
+
+    /// Check if notifications for the topic may be sent with this
+    /// certificate.
+    ///
+    /// Certificates without a topic extension only cover their UID.
+    /// Returns true if the certificate contains neither.
+    pub fn covers_topic(&self, topic: &str) -> bool {
+        if !self.topics.is_empty() {
+            self.topics.iter().any(|t| t == topic)
+        } else {
+            self.uid.as_ref().map(|uid| uid == topic).unwrap_or(true)
+        }
+    }
+}
+
+fn parse_p12(data: &[u8], passphrase: Option<&String>) -> Result<X509, CredentialError> {
+    let pass = passphrase.map(|p| p.as_str()).unwrap_or("");
+    Pkcs12::from_der(data)?
+        .parse2(pass)?
+        .cert
+        .ok_or(CredentialError::MissingCertificate)
+}
+
+/// Read a single DER TLV, returning the tag, the contents and the remaining
+/// data.
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7F;
+        if n == 0 || n > 4 || data.len() < 2 + n {
+            return None;
+        }
+        let len = data[2..2 + n].iter().fold(0, |acc, b| (acc << 8) | *b as usize);
+        (len, 2 + n)
+    };
+    if data.len() < header + len {
+        return None;
+    }
+    Some((tag, &data[header..header + len], &data[header + len..]))
+}
+
+/// Iterate over the TLVs contained in a constructed value.
+fn children(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut items = Vec::new();
+    while let Some((tag, content, rest)) = read_tlv(data) {
+        items.push((tag, content));
+        data = rest;
+    }
+    items
+}
+
+/// Extract the (OID, value) pairs of all extensions of a DER certificate.
+fn extensions(der: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let tbs = match read_tlv(der).and_then(|(_, cert, _)| read_tlv(cert)) {
+        Some((TAG_SEQUENCE, tbs, _)) => tbs,
+        _ => return Vec::new(),
+    };
+    let exts = match children(tbs)
+        .into_iter()
+        .find(|&(tag, _)| tag == TAG_EXTENSIONS)
+        .and_then(|(_, explicit)| read_tlv(explicit))
+    {
+        Some((TAG_SEQUENCE, exts, _)) => exts,
+        _ => return Vec::new(),
+    };
+
+    children(exts)
+        .into_iter()
+        .filter_map(|(_, ext)| {
+            let fields = children(ext);
+            let oid = fields.iter().find(|f| f.0 == TAG_OID)?.1;
+            // The optional `critical` boolean sits between OID and value.
+            let value = fields.iter().find(|f| f.0 == TAG_OCTET_STRING)?.1;
+            Some((oid, value))
+        })
+        .collect()
+}
+
+/// Parse the topic extension, a sequence of topic strings each followed by
+/// a sequence describing the topic type.
+fn parse_topics(value: &[u8]) -> Vec<String> {
+    match read_tlv(value) {
+        Some((TAG_SEQUENCE, content, _)) => children(content)
+            .into_iter()
+            .filter(|&(tag, _)| tag == TAG_UTF8_STRING)
+            .filter_map(|(_, s)| ::std::str::from_utf8(s).ok().map(|s| s.to_string()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::asn1::{Asn1Object, Asn1OctetString};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509Extension, X509NameBuilder};
+
+    fn der_string(tag: u8, s: &str) -> Vec<u8> {
+        let mut v = vec![tag, s.len() as u8];
+        v.extend_from_slice(s.as_bytes());
+        v
+    }
+
+    #[test]
+    fn test_certificate_info() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "Apple Push Services: com.example.app")
+            .unwrap();
+        name.append_entry_by_nid(Nid::USERID, "com.example.app").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(30).unwrap()).unwrap();
+
+        let mut topics = Vec::new();
+        topics.extend(der_string(TAG_UTF8_STRING, "com.example.app"));
+        topics.extend(vec![TAG_SEQUENCE, 5]);
+        topics.extend(der_string(TAG_UTF8_STRING, "app"));
+        topics.extend(der_string(TAG_UTF8_STRING, "com.example.app.voip"));
+        topics.extend(vec![TAG_SEQUENCE, 6]);
+        topics.extend(der_string(TAG_UTF8_STRING, "voip"));
+        let mut topics_der = vec![TAG_SEQUENCE, topics.len() as u8];
+        topics_der.extend(topics);
+
+        let null = [0x05, 0x00];
+        for &(oid, value) in &[
+            ("1.2.840.113635.100.6.3.1", &null[..]),
+            ("1.2.840.113635.100.6.3.2", &null[..]),
+            ("1.2.840.113635.100.6.3.6", &topics_der[..]),
+        ] {
+            let oid = Asn1Object::from_str(oid).unwrap();
+            let value = Asn1OctetString::new_from_bytes(value).unwrap();
+            let ext = X509Extension::new_from_der(&oid, false, &value).unwrap();
+            builder.append_extension(ext).unwrap();
+        }
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        let info = CertificateInfo::from_x509(&cert).unwrap();
+        assert_eq!(info.subject, "Apple Push Services: com.example.app");
+        assert_eq!(info.uid, Some("com.example.app".to_string()));
+        assert_eq!(info.environment, Some(CertificateEnvironment::Universal));
+        assert_eq!(info.topics, vec!["com.example.app", "com.example.app.voip"]);
+        assert!(info.covers_topic("com.example.app.voip"));
+        assert!(!info.covers_topic("com.example.other"));
+        let remaining = info.expires_in().unwrap();
+        assert!(remaining > Duration::from_secs(29 * 86_400));
+    }
+}
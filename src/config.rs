@@ -0,0 +1,435 @@
+//! Client configuration that can be loaded from files or the environment.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use error::{BoxError, ConfigError, CredentialError, Error};
+
+use auth::{redacted, Auth, ProviderCertificate, ProviderToken};
+use options::{ClientOptions, Proxy};
+use retry::RetryPolicy;
+use types::{APN_URL_DEV, APN_URL_PRODUCTION};
+use ApnsSync;
+
+/// The APNS environment to send to.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    #[default]
+    Production,
+    /// The development sandbox, for apps signed with a development profile.
+    Sandbox,
+}
+
+impl Environment {
+    /// The API endpoint of the environment.
+    pub fn url(self) -> &'static str {
+        match self {
+            Environment::Production => APN_URL_PRODUCTION,
+            Environment::Sandbox => APN_URL_DEV,
+        }
+    }
+
+    /// The other environment.
+    pub fn other(self) -> Environment {
+        match self {
+            Environment::Production => Environment::Sandbox,
+            Environment::Sandbox => Environment::Production,
+        }
+    }
+}
+
+/// File format of a provider certificate.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CertificateFileFormat {
+    #[default]
+    P12,
+    Pem,
+}
+
+/// Authentication settings.
+///
+/// Secrets can be given inline, or read from an environment variable
+/// (`passphrase_env`, `key_env`) to keep them out of configuration files.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum AuthConfig {
+    Certificate {
+        path: PathBuf,
+        /// Defaults to `p12`, or to `pem` with a `key_path`.
+        #[serde(default)]
+        format: Option<CertificateFileFormat>,
+        /// Separate PEM private key.
+        key_path: Option<PathBuf>,
+        passphrase: Option<String>,
+        passphrase_env: Option<String>,
+    },
+    Token {
+        key_id: String,
+        team_id: String,
+        /// Path of the .p8 file.
+        key_path: Option<PathBuf>,
+        /// The PEM encoded key.
+        key: Option<String>,
+        key_env: Option<String>,
+    },
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthConfig::Certificate {
+                path,
+                format,
+                key_path,
+                passphrase,
+                passphrase_env,
+            } => f
+                .debug_struct("Certificate")
+                .field("path", path)
+                .field("format", format)
+                .field("key_path", key_path)
+                .field("passphrase", &redacted(passphrase))
+                .field("passphrase_env", passphrase_env)
+                .finish(),
+            AuthConfig::Token {
+                key_id,
+                team_id,
+                key_path,
+                key,
+                key_env,
+            } => f
+                .debug_struct("Token")
+                .field("key_id", key_id)
+                .field("team_id", team_id)
+                .field("key_path", key_path)
+                .field("key", &redacted(key))
+                .field("key_env", key_env)
+                .finish(),
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Load the credentials.
+    pub fn auth(&self) -> Result<Auth, Error> {
+        match self {
+            AuthConfig::Certificate {
+                path,
+                format,
+                key_path,
+                passphrase,
+                passphrase_env,
+            } => {
+                let passphrase = match passphrase_env {
+                    Some(var) => Some(env::var(var).map_err(|source| CredentialError::Env {
+                        var: var.clone(),
+                        source,
+                    })?),
+                    None => passphrase.clone(),
+                };
+                let cert = match (format, key_path) {
+                    (Some(CertificateFileFormat::P12), Some(_)) => {
+                        return Err(ConfigError::KeyWithP12.into())
+                    }
+                    (_, Some(key)) => ProviderCertificate::pem_with_key(path, key, passphrase),
+                    (Some(CertificateFileFormat::Pem), None) => {
+                        ProviderCertificate::pem(path, passphrase)
+                    }
+                    (_, None) => ProviderCertificate::p12(path, passphrase),
+                };
+                Ok(Auth::ProviderCertificate(cert))
+            }
+            AuthConfig::Token {
+                key_id,
+                team_id,
+                key_path,
+                key,
+                key_env,
+            } => {
+                let token = match (key_path, key, key_env) {
+                    (Some(path), _, _) => {
+                        ProviderToken::from_file(key_id.as_str(), team_id.as_str(), path)?
+                    }
+                    (_, Some(key), _) => {
+                        ProviderToken::new(key_id.as_str(), team_id.as_str(), key)
+                    }
+                    (_, _, Some(var)) => {
+                        ProviderToken::from_env(key_id.as_str(), team_id.as_str(), var)?
+                    }
+                    _ => return Err(ConfigError::MissingTokenKey.into()),
+                };
+                Ok(Auth::ProviderToken(token))
+            }
+        }
+    }
+}
+
+/// Configuration of an `ApnsSync` client.
+///
+/// ```json
+/// {
+///   "auth": { "method": "token", "key_id": "KEYID", "team_id": "TEAMID", "key_path": "AuthKey.p8" },
+///   "environment": "sandbox",
+///   "default_topic": "com.example.app",
+///   "timeout_ms": 10000,
+///   "retry": { "max_retries": 3 }
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApnsConfig {
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub environment: Environment,
+    /// Custom API endpoint, e.g. a local emulator.
+    pub endpoint: Option<String>,
+    /// PEM CA certificate to trust for the endpoint.
+    pub ca_certificate: Option<PathBuf>,
+    /// Topic for notifications without one.
+    pub default_topic: Option<String>,
+    /// Maximum time for a request, in milliseconds.
+    pub timeout_ms: Option<u64>,
+    /// Maximum time for connecting, in milliseconds.
+    pub connect_timeout_ms: Option<u64>,
+    /// Maximum time for a send including retries, in milliseconds.
+    pub total_timeout_ms: Option<u64>,
+    /// Interval of TCP keepalive probes, in milliseconds.
+    pub tcp_keepalive_ms: Option<u64>,
+    /// Interval of HTTP/2 PING frames, in milliseconds.
+    pub ping_interval_ms: Option<u64>,
+    /// Maximum idle time of a connection, in milliseconds.
+    pub max_idle_ms: Option<u64>,
+    pub proxy: Option<Proxy>,
+    pub retry: Option<RetryPolicy>,
+}
+
+fn env_error<E: Into<BoxError>>(var: &str, source: E) -> ConfigError {
+    ConfigError::Env {
+        var: var.to_string(),
+        source: source.into(),
+    }
+}
+
+fn read_env(var: &str) -> Result<String, ConfigError> {
+    env::var(var).map_err(|e| env_error(var, e))
+}
+
+fn parse_env<T: ::std::str::FromStr>(var: &str) -> Result<Option<T>, ConfigError>
+where
+    T::Err: Into<BoxError>,
+{
+    match env::var(var) {
+        Ok(value) => value.parse().map(Some).map_err(|e| env_error(var, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+impl ApnsConfig {
+    /// Load the configuration from a file.
+    ///
+    /// The format is chosen by the extension: `.json`, `.toml` (with the
+    /// `toml` feature) or `.yaml`/`.yml` (with the `yaml` feature).
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let parsed: Result<Self, BoxError> = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => ::serde_json::from_str(&data).map_err(Into::into),
+            #[cfg(feature = "toml")]
+            Some("toml") => ::toml::from_str(&data).map_err(Into::into),
+            #[cfg(feature = "yaml")]
+            Some("yaml") | Some("yml") => ::serde_yaml::from_str(&data).map_err(Into::into),
+            _ => return Err(ConfigError::UnsupportedFormat(path.to_path_buf())),
+        };
+        parsed.map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Load the configuration from `APNS_*` environment variables.
+    ///
+    /// Certificate authentication uses `APNS_CERT_PATH`, `APNS_CERT_FORMAT`
+    /// (`p12` or `pem`), `APNS_KEY_PATH` and `APNS_CERT_PW`. Token
+    /// authentication is used if `APNS_KEY_ID` is set, with `APNS_TEAM_ID`
+    /// and either `APNS_TOKEN_KEY_PATH` or the PEM key in `APNS_TOKEN_KEY`.
+    ///
+    /// The other settings are read from `APNS_ENVIRONMENT` (`production` or
+    /// `sandbox`), `APNS_ENDPOINT`, `APNS_CA_CERT`, `APNS_TOPIC`,
+    /// `APNS_TIMEOUT_MS`, `APNS_CONNECT_TIMEOUT_MS`, `APNS_TOTAL_TIMEOUT_MS`,
+    /// `APNS_TCP_KEEPALIVE_MS`, `APNS_PING_INTERVAL_MS`, `APNS_MAX_IDLE_MS`,
+    /// `APNS_PROXY` (the proxy URL, which may contain credentials),
+    /// `APNS_NO_PROXY` (comma separated hosts) and `APNS_MAX_RETRIES`.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let path = |var| env::var_os(var).map(PathBuf::from);
+
+        let auth = match env::var("APNS_KEY_ID") {
+            Ok(key_id) => AuthConfig::Token {
+                key_id,
+                team_id: read_env("APNS_TEAM_ID")?,
+                key_path: path("APNS_TOKEN_KEY_PATH"),
+                key: None,
+                key_env: env::var_os("APNS_TOKEN_KEY").map(|_| "APNS_TOKEN_KEY".to_string()),
+            },
+            Err(_) => AuthConfig::Certificate {
+                path: PathBuf::from(read_env("APNS_CERT_PATH")?),
+                format: match env::var("APNS_CERT_FORMAT") {
+                    Ok(format) => Some(
+                        ::serde_json::from_value(format.into())
+                            .map_err(|e| env_error("APNS_CERT_FORMAT", e))?,
+                    ),
+                    Err(_) => None,
+                },
+                key_path: path("APNS_KEY_PATH"),
+                passphrase: env::var("APNS_CERT_PW").ok(),
+                passphrase_env: None,
+            },
+        };
+        let environment = match env::var("APNS_ENVIRONMENT") {
+            Ok(environment) => ::serde_json::from_value(environment.into())
+                .map_err(|e| env_error("APNS_ENVIRONMENT", e))?,
+            Err(_) => Environment::default(),
+        };
+
+        Ok(ApnsConfig {
+            auth,
+            environment,
+            endpoint: env::var("APNS_ENDPOINT").ok(),
+            ca_certificate: path("APNS_CA_CERT"),
+            default_topic: env::var("APNS_TOPIC").ok(),
+            timeout_ms: parse_env("APNS_TIMEOUT_MS")?,
+            connect_timeout_ms: parse_env("APNS_CONNECT_TIMEOUT_MS")?,
+            total_timeout_ms: parse_env("APNS_TOTAL_TIMEOUT_MS")?,
+            tcp_keepalive_ms: parse_env("APNS_TCP_KEEPALIVE_MS")?,
+            ping_interval_ms: parse_env("APNS_PING_INTERVAL_MS")?,
+            max_idle_ms: parse_env("APNS_MAX_IDLE_MS")?,
+            proxy: env::var("APNS_PROXY").ok().map(|url| Proxy {
+                no_proxy: env::var("APNS_NO_PROXY")
+                    .map(|hosts| hosts.split(',').map(|h| h.trim().to_string()).collect())
+                    .unwrap_or_default(),
+                ..Proxy::new(url)
+            }),
+            retry: parse_env("APNS_MAX_RETRIES")?.map(RetryPolicy::new),
+        })
+    }
+
+    /// The timeouts and connection settings.
+    pub fn options(&self) -> ClientOptions {
+        let millis = |ms: Option<u64>| ms.map(Duration::from_millis);
+        ClientOptions {
+            connect_timeout: millis(self.connect_timeout_ms),
+            request_timeout: millis(self.timeout_ms),
+            total_timeout: millis(self.total_timeout_ms),
+            tcp_keepalive: millis(self.tcp_keepalive_ms),
+            ping_interval: millis(self.ping_interval_ms),
+            max_idle: millis(self.max_idle_ms),
+            proxy: self.proxy.clone(),
+        }
+    }
+}
+
+impl ApnsSync {
+    /// Create a client from a configuration.
+    pub fn from_config(config: &ApnsConfig) -> Result<Self, Error> {
+        let mut apns = ApnsSync::new(config.auth.auth()?)?;
+        apns.set_production(config.environment == Environment::Production);
+        apns.set_endpoint(config.endpoint.clone());
+        if let Some(ref path) = config.ca_certificate {
+            apns.set_ca_certificate(path)?;
+        }
+        apns.set_default_topic(config.default_topic.clone());
+        apns.set_options(config.options())?;
+        apns.set_retry_policy(config.retry.clone());
+        Ok(apns)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use auth::CertificateFormat;
+
+    #[test]
+    fn test_parse_config() {
+        let config: ApnsConfig = ::serde_json::from_str(
+            r#"{
+                "auth": { "method": "token", "key_id": "KEYID", "team_id": "TEAMID", "key": "" },
+                "environment": "sandbox",
+                "default_topic": "com.example.app",
+                "retry": { "max_retries": 3 }
+            }"#,
+        ).unwrap();
+        assert_eq!(config.environment, Environment::Sandbox);
+        assert_eq!(config.retry.as_ref().unwrap().max_retries, 3);
+        assert_eq!(config.retry.as_ref().unwrap().initial_backoff_ms, 100);
+
+        let apns = ApnsSync::from_config(&config).unwrap();
+        assert_eq!(apns.default_topic(), Some("com.example.app".to_string()));
+    }
+
+    #[test]
+    fn test_debug_secrets() {
+        let config: ApnsConfig = ::serde_json::from_str(
+            r#"{
+                "auth": {
+                    "method": "token", "key_id": "KEYID", "team_id": "TEAMID", "key": "SECRET"
+                },
+                "proxy": { "url": "http://proxy:3128", "username": "user", "password": "SECRET" }
+            }"#,
+        ).unwrap();
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("KEYID"), "{}", debug);
+        assert!(!debug.contains("SECRET"), "{}", debug);
+
+        let auth = AuthConfig::Certificate {
+            path: "cert.p12".into(),
+            format: Some(CertificateFileFormat::P12),
+            key_path: None,
+            passphrase: Some("SECRET".into()),
+            passphrase_env: None,
+        };
+        assert!(!format!("{:?}", auth).contains("SECRET"));
+        assert!(!format!("{:?}", auth.auth().unwrap()).contains("SECRET"));
+    }
+
+    #[test]
+    fn test_certificate_format() {
+        let auth = |format: &str| {
+            let config = format!(
+                r#"{{ "method": "certificate", "path": "cert.pem", "key_path": "key.pem" {} }}"#,
+                format
+            );
+            ::serde_json::from_str::<AuthConfig>(&config).unwrap().auth()
+        };
+        let pem_with_key = |auth: Result<Auth, Error>| match auth {
+            Ok(Auth::ProviderCertificate(cert)) => {
+                matches!(cert.format, CertificateFormat::PemCertKey { .. })
+            }
+            _ => false,
+        };
+        assert!(pem_with_key(auth("")));
+        assert!(pem_with_key(auth(r#", "format": "pem""#)));
+        match auth(r#", "format": "p12""#) {
+            Err(Error::Config(ConfigError::KeyWithP12)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_config_error() {
+        use std::error::Error as StdError;
+
+        let error = ApnsConfig::from_file("missing.json").unwrap_err();
+        match error {
+            ConfigError::Read { ref path, .. } => assert_eq!(path, Path::new("missing.json")),
+            ref e => panic!("Unexpected error: {}", e),
+        }
+        assert!(error.source().is_some());
+    }
+}
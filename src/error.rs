@@ -1,4 +1,14 @@
-use failure::Error;
+use std::env::VarError;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openssl::error::ErrorStack;
+
+use config::Environment;
+use validate::Issue;
 
 /// The reason for a failure returned by the APN api.
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -111,6 +121,16 @@ impl ApiErrorReason {
             _ => false,
         }
     }
+
+    /// Returns true if the reason indicates that the configured credentials
+    /// are unusable, so every further request would fail the same way.
+    pub fn is_fatal_auth(&self) -> bool {
+        use self::ApiErrorReason::*;
+        matches!(
+            *self,
+            BadCertificate | BadCertificateEnvironment | InvalidProviderToken | Forbidden
+        )
+    }
 }
 
 impl ::std::fmt::Display for ApiErrorReason {
@@ -120,11 +140,17 @@ impl ::std::fmt::Display for ApiErrorReason {
 }
 
 /// Error returned by the APN api.
-#[derive(Fail, Serialize, Deserialize, Clone, Debug)]
-#[fail(display = "{} (status: {}", reason, status)]
+#[derive(Error, Serialize, Deserialize, Clone, Debug)]
+#[error("{reason} (status {status})")]
 pub struct ApiError {
     pub status: u32,
     pub reason: ApiErrorReason,
+    /// Delay requested with the `Retry-After` header.
+    #[serde(default)]
+    pub retry_after: Option<Duration>,
+    /// Headers of the response, with lowercase names.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
 }
 
 impl ApiError {
@@ -150,12 +176,158 @@ impl ErrorResponse {
     }
 }
 
-#[derive(Fail, Debug)]
+/// Error returned when the client side rate limit is exceeded.
+#[derive(Error, Clone, Debug)]
+#[error("Rate limit exceeded (retry after {retry_after:?})")]
+pub struct RateLimitError {
+    /// Time after which the notification would be accepted.
+    pub retry_after: Duration,
+}
+
+/// Error returned while the circuit breaker is open.
+///
+/// The breaker trips after the API rejected the client credentials.
+#[derive(Error, Clone, Debug)]
+#[error("Circuit breaker open after fatal API error: {reason}")]
+pub struct CircuitOpenError {
+    /// The reason that tripped the circuit breaker.
+    pub reason: ApiErrorReason,
+}
+
+/// Error returned by `ApnsRouter` if no client is registered for a topic.
+#[derive(Error, Clone, Debug)]
+#[error("No client registered for topic {topic:?} ({environment:?})")]
+pub struct RoutingError {
+    /// Topic of the notification, `None` if it has no topic.
+    pub topic: Option<String>,
+    pub environment: Environment,
+}
+
+/// Error returned when a send exceeds one of the timeouts of the
+/// `ClientOptions`.
+#[derive(Error, Clone, Debug)]
+#[error("Request timed out after {elapsed:?}")]
+pub struct TimeoutError {
+    /// Time after which the request, or the whole send, was aborted.
+    pub elapsed: Duration,
+}
+
+/// Error returned when a notification fails the checks of
+/// `validate_notification`.
+#[derive(Error, Clone, Debug)]
+#[error("Invalid notification: {}", join_issues(issues))]
+pub struct ValidationError {
+    /// The issues with severity `Error`.
+    pub issues: Vec<Issue>,
+}
+
+fn join_issues(issues: &[Issue]) -> String {
+    let messages: Vec<_> = issues.iter().map(|i| i.message.as_str()).collect();
+    messages.join(", ")
+}
+
+/// Problem with the provider certificate, detected before sending.
+#[derive(Error, Clone, Debug)]
+pub enum CertificateError {
+    #[error("Provider certificate expired")]
+    Expired,
+    #[error("Provider certificate expires in {days} days")]
+    ExpiresSoon { days: u64 },
+    #[error("Provider certificate does not cover topic {0}")]
+    TopicNotCovered(String),
+}
+
+/// Error loading the provider credentials.
+#[derive(Error, Debug)]
+pub enum CredentialError {
+    #[error("Could not read {}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Environment variable {var} is not available")]
+    Env {
+        var: String,
+        #[source]
+        source: VarError,
+    },
+    #[error("Invalid provider key or certificate")]
+    Crypto(#[from] ErrorStack),
+    #[error("PKCS#12 archive does not contain a certificate")]
+    MissingCertificate,
+}
+
+/// Boxed error of another library, e.g. a configuration file parser.
+pub type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Error loading an `ApnsConfig`.
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Could not read {}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Invalid configuration in {}", path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Unsupported configuration format: {}", _0.display())]
+    UnsupportedFormat(PathBuf),
+    #[error("Invalid environment variable {var}")]
+    Env {
+        var: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Token authentication requires key_path, key or key_env")]
+    MissingTokenKey,
+    #[error("A separate key_path requires the pem certificate format")]
+    KeyWithP12,
+}
+
+/// Error creating or configuring a client.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Credential(#[from] CredentialError),
+    #[error("Could not configure the connection")]
+    Transport(#[from] ::curl::Error),
+}
+
+/// Error sending a notification.
+#[derive(Error, Debug)]
 pub enum SendError {
-    #[fail(display = "{}", _0)]
-    Api(ApiError),
-    #[fail(display = "{}", _0)]
-    Other(Error),
+    #[error(transparent)]
+    Api(#[from] ApiError),
+    #[error(transparent)]
+    RateLimited(#[from] RateLimitError),
+    #[error(transparent)]
+    CircuitOpen(#[from] CircuitOpenError),
+    #[error(transparent)]
+    Certificate(#[from] CertificateError),
+    #[error(transparent)]
+    Routing(#[from] RoutingError),
+    #[error(transparent)]
+    Timeout(#[from] TimeoutError),
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    #[error(transparent)]
+    Credential(#[from] CredentialError),
+    /// The connection failed, or was closed before the response arrived.
+    #[error("Request failed")]
+    Transport(#[from] ::curl::Error),
+    #[error("Could not serialize the notification")]
+    Serialization(#[from] ::serde_json::Error),
+    /// Error of an `Interceptor`.
+    #[error(transparent)]
+    Other(BoxError),
 }
 
 impl SendError {
@@ -172,22 +344,104 @@ impl SendError {
             _ => false,
         }
     }
+
+    /// Suggested delay before sending the notification again: the
+    /// `Retry-After` of an API error, or the wait time of the client side
+    /// rate limit.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self {
+            SendError::Api(ref e) => e.retry_after,
+            SendError::RateLimited(ref e) => Some(e.retry_after),
+            _ => None,
+        }
+    }
 }
 
-impl From<::curl::Error> for SendError {
-    fn from(e: ::curl::Error) -> Self {
-        SendError::Other(e.into())
+/// Parse a `Retry-After` header value, either a number of seconds or an
+/// HTTP date (`Sun, 06 Nov 1994 08:49:37 GMT`).
+///
+/// Dates in the past result in a zero delay. Invalid dates, and dates too
+/// far in the future to represent, result in `None`.
+pub(crate) fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+    let day: u64 = parts[1].parse().ok()?;
+    let month = MONTHS.iter().position(|&m| m == parts[2])? as u64 + 1;
+    let year: u64 = parts[3].parse().ok()?;
+    let time: Vec<u64> = parts[4]
+        .split(':')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    if time.len() != 3 || year < 1970 || !(1..=31).contains(&day) {
+        return None;
+    }
+    if time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
     }
+
+    // Days since the UNIX epoch of the civil date.
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let days = y
+        .checked_mul(365)?
+        .checked_add(y / 4 - y / 100 + y / 400)?
+        .checked_add((153 * m + 2) / 5 + day - 1)?
+        .checked_sub(719_468)?;
+    let seconds = days
+        .checked_mul(86_400)?
+        .checked_add(time[0] * 3600 + time[1] * 60 + time[2])?;
+    let date = UNIX_EPOCH.checked_add(Duration::from_secs(seconds))?;
+    Some(date.duration_since(now).unwrap_or_default())
 }
 
-impl From<::serde_json::Error> for SendError {
-    fn from(e: ::serde_json::Error) -> Self {
-        SendError::Other(e.into())
+/// Displays an error followed by its sources, separated by colons.
+pub(crate) struct Chain<'a>(pub &'a (dyn StdError + 'static));
+
+impl<'a> fmt::Display for Chain<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(e) = source {
+            write!(f, ": {}", e)?;
+            source = e.source();
+        }
+        Ok(())
     }
 }
 
-impl From<ApiError> for SendError {
-    fn from(e: ApiError) -> Self {
-        SendError::Api(e)
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:37 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:40:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 10000000000000000000 08:49:37 GMT", now),
+            None
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 99999999999999999999:49:37 GMT", now),
+            None
+        );
     }
 }
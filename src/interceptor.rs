@@ -0,0 +1,71 @@
+//! Hooks around `ApnsSync::send`.
+
+use error::SendError;
+use types::{Notification, SendResponse};
+
+/// Intercepts notifications before they are sent, and results after.
+///
+/// Interceptors are registered with `ApnsSync::add_interceptor` and run in
+/// registration order before the send, and in reverse order after it, like
+/// nested layers.
+pub trait Interceptor: Send + Sync {
+    /// Called before the notification is validated and serialized.
+    ///
+    /// The notification may be modified. Returning a result skips the
+    /// remaining interceptors and the actual send; only the interceptors
+    /// that already ran see the result in `after_send`.
+    fn before_send(
+        &self,
+        _notification: &mut Notification,
+    ) -> Option<Result<SendResponse, SendError>> {
+        None
+    }
+
+    /// Called with the result of the send, which may be replaced.
+    fn after_send(
+        &self,
+        _notification: &Notification,
+        _result: &mut Result<SendResponse, SendError>,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use types::NotificationBuilder;
+
+    /// Only allows a single topic, and adds a tenant to the custom data.
+    struct Tenant(Mutex<Vec<String>>);
+
+    impl Interceptor for Tenant {
+        fn before_send(&self, n: &mut Notification) -> Option<Result<SendResponse, SendError>> {
+            if n.topic.as_deref() != Some("com.example.app") {
+                return Some(Err(SendError::Other("Topic not allowed".into())));
+            }
+            n.data.insert("tenant".into(), "a".into());
+            None
+        }
+
+        fn after_send(&self, n: &Notification, result: &mut Result<SendResponse, SendError>) {
+            let entry = format!("{} {}", n.data["tenant"], result.is_ok());
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn test_interceptors() {
+        let mut apns = ::test::client();
+        apns.disable_delivery_for_testing();
+        let tenant = Arc::new(Tenant(Mutex::new(Vec::new())));
+        apns.add_interceptor(tenant.clone());
+
+        let n = NotificationBuilder::new("com.example.app".into(), "abcdef".into()).build();
+        assert!(apns.send(n).is_ok());
+        let n = NotificationBuilder::new("com.example.other".into(), "abcdef".into()).build();
+        assert!(apns.send(n).is_err());
+        assert_eq!(*tenant.0.lock().unwrap(), vec!["\"a\" true"]);
+    }
+}
@@ -1,103 +1,228 @@
 #![deny(warnings)]
 
+extern crate base64;
 extern crate curl;
+extern crate curl_sys;
 #[macro_use]
-extern crate failure;
+extern crate log;
+extern crate openssl;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+#[cfg(feature = "yaml")]
+extern crate serde_yaml;
+#[macro_use]
+extern crate thiserror;
+#[cfg(feature = "toml")]
+extern crate toml;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 extern crate uuid;
 
 mod types;
 pub use self::types::*;
 
 mod error;
-use self::error::*;
+pub use self::error::*;
+
+mod auth;
+pub use self::auth::{Auth, CertificateData, CertificateFormat, ProviderCertificate, ProviderToken};
+use self::auth::TokenCache;
+
+mod certificate;
+pub use self::certificate::{CertificateEnvironment, CertificateInfo, CertificatePolicy};
+
+mod validate;
+pub use self::validate::{validate_notification, validate_payload, Issue, Severity,
+                         MAX_COLLAPSE_ID_LENGTH, MAX_PAYLOAD_SIZE, MAX_VOIP_PAYLOAD_SIZE};
+
+mod ratelimit;
+pub use self::ratelimit::{RateLimit, RateLimitMode};
+use self::ratelimit::RateLimiter;
+
+mod options;
+pub use self::options::{ClientOptions, Proxy};
+
+mod interceptor;
+pub use self::interceptor::Interceptor;
+
+mod metrics;
+pub use self::metrics::{Metrics, SendEvent, SendOutcome};
+
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "prometheus")]
+pub use self::prometheus::PrometheusMetrics;
+
+mod config;
+pub use self::config::{ApnsConfig, AuthConfig, CertificateFileFormat, Environment};
+
+mod router;
+pub use self::router::{ApnsPool, ApnsRouter};
+
+mod retry;
+pub use self::retry::RetryPolicy;
+
+mod trace;
+use self::trace::SendSpan;
+
+#[cfg(test)]
+mod test_util;
 
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use uuid::Uuid;
-use failure::Error;
-use curl::easy::{Easy2, Handler, HttpVersion, List, WriteError};
+use curl::easy::{Easy2, Handler, HttpVersion, InfoType, List, WriteError};
 
 /// Writer used by curl.
-struct Collector(Vec<u8>);
+#[derive(Default)]
+struct Collector {
+    body: Vec<u8>,
+    /// Response headers, with lowercase names.
+    headers: Vec<(String, String)>,
+}
 
 impl Handler for Collector {
     fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
-        self.0.extend_from_slice(data);
+        self.body.extend_from_slice(data);
         Ok(data.len())
     }
-}
-
-#[derive(Clone, Debug)]
-pub struct ProviderCertificate {
-    pub p12_path: PathBuf,
-    pub passphrase: Option<String>,
-}
-
-#[derive(Clone, Debug)]
-pub enum Auth {
-    ProviderCertificate(ProviderCertificate),
-}
 
-impl Auth {
-    fn as_cert(&self) -> &ProviderCertificate {
-        match self {
-            &Auth::ProviderCertificate(ref c) => c,
+    fn header(&mut self, data: &[u8]) -> bool {
+        let line = String::from_utf8_lossy(data);
+        if line.starts_with("HTTP/") {
+            // Status line of a new response.
+            self.headers.clear();
+        } else if let Some((name, value)) = line.split_once(':') {
+            self.headers
+                .push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
         }
+        true
+    }
+
+    fn debug(&mut self, kind: InfoType, data: &[u8]) {
+        trace::curl_debug(kind, data);
     }
 }
 
 pub struct ApnsSync {
     production: bool,
+    /// Custom API endpoint, overriding production/development.
+    endpoint: Option<String>,
+    /// Custom API endpoints of single environments.
+    environment_endpoints: HashMap<Environment, String>,
+    /// Additional CA certificate to trust.
+    ca_certificate: Option<PathBuf>,
     verbose: bool,
     delivery_disabled: bool,
-    auth: Auth,
+    auth: RefCell<Auth>,
+    token: RefCell<TokenCache>,
+    /// Modification time of the credential files, if they are watched.
+    watched_credentials: Option<Cell<Option<SystemTime>>>,
+    /// Force a new connection for the next request.
+    reconnect: Cell<bool>,
+    /// Shared with the other clients of an `ApnsPool`.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    certificate: RefCell<Option<CertificateInfo>>,
+    /// Topic for notifications without one, overriding the certificate.
+    default_topic: Option<String>,
+    certificate_policy: CertificatePolicy,
+    /// Minimum remaining validity of the certificate before it is reported.
+    certificate_min_validity: Duration,
+    /// Set once the certificate expiry has been logged.
+    expiry_warned: Cell<bool>,
+    /// Reason of the fatal API error that tripped the circuit breaker.
+    circuit: RefCell<Option<ApiErrorReason>>,
+    /// Retry in the other environment on environment errors.
+    environment_fallback: bool,
+    options: ClientOptions,
+    retry_policy: Option<RetryPolicy>,
+    metrics: Option<Arc<dyn Metrics>>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
     easy: RefCell<Easy2<Collector>>,
 }
 
 impl ApnsSync {
     pub fn new(auth: Auth) -> Result<Self, Error> {
-        let mut easy = Easy2::new(Collector(Vec::new()));
-
-        easy.http_version(HttpVersion::V2)?;
-        // easy.connect_only(true)?;
-        // easy.url(APN_URL_PRODUCTION)?;
-
-        // Configure curl for client certificate.
-        {
-            let cert = auth.as_cert();
-
-            easy.ssl_cert(&cert.p12_path)?;
-            if let Some(ref pw) = cert.passphrase.as_ref() {
-                easy.key_password(&pw)?;
-            }
-        }
+        let easy = Self::new_handle(&auth, None)?;
 
+        let certificate = load_certificate(&auth);
         let apns = ApnsSync {
             production: true,
+            endpoint: None,
+            environment_endpoints: HashMap::new(),
+            ca_certificate: None,
             verbose: false,
             delivery_disabled: false,
-            auth,
+            auth: RefCell::new(auth),
+            token: RefCell::new(TokenCache::default()),
+            watched_credentials: None,
+            reconnect: Cell::new(false),
+            rate_limiter: None,
+            certificate: RefCell::new(certificate),
+            default_topic: None,
+            certificate_policy: CertificatePolicy::Warn,
+            certificate_min_validity: Duration::from_secs(30 * 24 * 60 * 60),
+            expiry_warned: Cell::new(false),
+            circuit: RefCell::new(None),
+            environment_fallback: false,
+            options: ClientOptions::default(),
+            retry_policy: None,
+            metrics: None,
+            interceptors: Vec::new(),
             easy: RefCell::new(easy),
         };
         Ok(apns)
     }
 
+    /// Create a curl handle with the base configuration and the
+    /// credentials.
+    ///
+    /// A new handle is created whenever the credentials change, since
+    /// connections and TLS sessions cached by the previous handle would
+    /// still authenticate with the old certificate.
+    fn new_handle(
+        auth: &Auth,
+        ca_certificate: Option<&Path>,
+    ) -> Result<Easy2<Collector>, curl::Error> {
+        let mut easy = Easy2::new(Collector::default());
+        easy.http_version(HttpVersion::V2)?;
+
+        if let Some(path) = ca_certificate {
+            easy.cainfo(path)?;
+        }
+
+        // Configure curl for client certificate.
+        auth.configure(&mut easy)?;
+        Ok(easy)
+    }
+
     pub fn with_certificate<P: AsRef<Path>>(
         path: P,
         passphrase: Option<String>,
     ) -> Result<ApnsSync, Error> {
-        Self::new(Auth::ProviderCertificate(ProviderCertificate {
-            p12_path: path.as_ref().to_path_buf(),
-            passphrase,
-        }))
+        Self::new(Auth::ProviderCertificate(ProviderCertificate::p12(
+            path, passphrase,
+        )))
+    }
+
+    /// Create a client using token based authentication.
+    pub fn with_token(token: ProviderToken) -> Result<ApnsSync, Error> {
+        Self::new(Auth::ProviderToken(token))
     }
 
-    /// Enable/disable verbose debug logging to stderr.
+    /// Enable/disable logging of connection and TLS events.
+    ///
+    /// The events are logged with the `apns2::connection` target at debug
+    /// level, and are also enabled whenever that level is enabled for the
+    /// target. Provider tokens and device tokens are redacted.
     pub fn set_verbose(&mut self, verbose: bool) {
         self.verbose = verbose;
     }
@@ -107,6 +232,362 @@ impl ApnsSync {
         self.production = production;
     }
 
+    /// The environment selected with `set_production`.
+    pub fn environment(&self) -> Environment {
+        if self.production {
+            Environment::Production
+        } else {
+            Environment::Sandbox
+        }
+    }
+
+    /// Enable/disable the environment fallback.
+    ///
+    /// When enabled, a notification rejected with `BadDeviceToken` or
+    /// `BadCertificateEnvironment` is sent again to the other environment.
+    /// The environment that accepted it is reported by `send_detailed`.
+    /// Has no effect with a custom endpoint set with `set_endpoint`.
+    pub fn set_environment_fallback(&mut self, fallback: bool) {
+        self.environment_fallback = fallback;
+    }
+
+    /// Use a custom API endpoint (e.g. `https://localhost:8443`) instead of
+    /// the production or development endpoint.
+    ///
+    /// Useful to send to a local emulator. Pass `None` to use the endpoint
+    /// selected with `set_production` again.
+    pub fn set_endpoint(&mut self, endpoint: Option<String>) {
+        self.endpoint = endpoint;
+    }
+
+    /// Use a custom API endpoint for a single environment, e.g. to test the
+    /// environment fallback against two emulators.
+    ///
+    /// Overridden by `set_endpoint`. Pass `None` to use the default endpoint
+    /// of the environment again.
+    pub fn set_environment_endpoint(&mut self, environment: Environment, endpoint: Option<String>) {
+        match endpoint {
+            Some(endpoint) => {
+                self.environment_endpoints.insert(environment, endpoint);
+            }
+            None => {
+                self.environment_endpoints.remove(&environment);
+            }
+        }
+    }
+
+    /// Trust the CA certificate(s) in the given PEM file when verifying the
+    /// server, instead of the system CA store.
+    pub fn set_ca_certificate<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref().to_path_buf();
+        self.easy.get_mut().cainfo(&path)?;
+        self.ca_certificate = Some(path);
+        Ok(())
+    }
+
+    /// Replace the credentials used for authentication.
+    ///
+    /// The new credentials are used starting with the next request, which
+    /// will open a new connection. This also resets the circuit breaker.
+    pub fn set_auth(&mut self, auth: Auth) -> Result<(), Error> {
+        *self.easy.get_mut() = Self::new_handle(&auth, self.ca_certificate.as_deref())?;
+        self.token.get_mut().clear();
+        *self.certificate.get_mut() = load_certificate(&auth);
+        self.expiry_warned.set(false);
+        if self.watched_credentials.is_some() {
+            if !auth.is_watchable() {
+                warn!("Credentials are watched, but the new ones are not read from a file");
+            }
+            self.watched_credentials = Some(Cell::new(auth.modified()));
+        }
+        *self.auth.get_mut() = auth;
+        self.reconnect.set(true);
+        self.reset_circuit();
+        Ok(())
+    }
+
+    /// Enable/disable watching the credential files for changes.
+    ///
+    /// When enabled, the modification time of the certificate and key files
+    /// (or the .p8 file of a `ProviderToken::from_file`) is checked before
+    /// each request. If it changed, the credentials are reloaded just like
+    /// with `set_auth`. Credentials held in memory can not be watched, which
+    /// is logged as a warning.
+    pub fn set_watch_credentials(&mut self, watch: bool) {
+        let auth = self.auth.get_mut();
+        if watch && !auth.is_watchable() {
+            warn!("Credentials can not be watched, since they are not read from a file");
+        }
+        self.watched_credentials = if watch {
+            Some(Cell::new(auth.modified()))
+        } else {
+            None
+        };
+    }
+
+    /// Reload the credentials if they are watched and were modified.
+    fn reload_modified_credentials(&self) -> Result<(), SendError> {
+        let watched = match self.watched_credentials {
+            Some(ref w) => w,
+            None => return Ok(()),
+        };
+        let mut auth = self.auth.borrow_mut();
+        let modified = auth.modified();
+        if modified == watched.get() {
+            return Ok(());
+        }
+
+        auth.reload()?;
+        *self.easy.borrow_mut() = Self::new_handle(&auth, self.ca_certificate.as_deref())?;
+        watched.set(modified);
+        debug!("Reloaded the modified credentials");
+        self.token.borrow_mut().clear();
+        *self.certificate.borrow_mut() = load_certificate(&auth);
+        self.expiry_warned.set(false);
+        self.reconnect.set(true);
+        self.reset_circuit();
+        Ok(())
+    }
+
+    /// Reload modified credentials, then fail if the circuit breaker is
+    /// open.
+    fn check_circuit(&self) -> Result<(), SendError> {
+        self.reload_modified_credentials()?;
+        match self.circuit_open() {
+            Some(reason) => Err(CircuitOpenError { reason }.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Information about the provider certificate.
+    ///
+    /// Returns `None` for token authentication, or if the certificate could
+    /// not be parsed.
+    pub fn certificate(&self) -> Option<CertificateInfo> {
+        self.certificate.borrow().clone()
+    }
+
+    /// Set the default topic (app bundle id) for notifications without a
+    /// topic.
+    ///
+    /// If not set, the topic is inferred from the provider certificate.
+    pub fn set_default_topic(&mut self, topic: Option<String>) {
+        self.default_topic = topic;
+    }
+
+    /// The default topic: either the configured one, or the UID (or first
+    /// listed topic) of the provider certificate.
+    pub fn default_topic(&self) -> Option<String> {
+        if let Some(ref topic) = self.default_topic {
+            return Some(topic.clone());
+        }
+        self.certificate
+            .borrow()
+            .as_ref()
+            .and_then(|c| c.uid.clone().or_else(|| c.topics.first().cloned()))
+    }
+
+    /// Determine the topic for a notification.
+    ///
+    /// Notifications without a topic use the default topic, combined with
+    /// the suffix for the push type (e.g. `.voip`), unless it already ends
+    /// with it. Returns `None` if no topic is known, in which case APNS uses the
+    /// certificate subject.
+    fn resolve_topic(&self, n: &Notification) -> Option<String> {
+        if let Some(ref topic) = n.topic {
+            return Some(topic.clone());
+        }
+        let suffix = n.push_type.map(|t| t.topic_suffix()).unwrap_or("");
+        self.default_topic().map(|topic| {
+            if topic.ends_with(suffix) {
+                topic
+            } else {
+                format!("{}{}", topic, suffix)
+            }
+        })
+    }
+
+    /// Configure how problems with the provider certificate are handled.
+    ///
+    /// Before each request, the certificate is checked for expiry (or
+    /// remaining validity below `min_validity`), and for covering the topic
+    /// of the notification. Defaults to `CertificatePolicy::Warn` with a
+    /// minimum validity of 30 days.
+    pub fn set_certificate_policy(&mut self, policy: CertificatePolicy, min_validity: Duration) {
+        self.certificate_policy = policy;
+        self.certificate_min_validity = min_validity;
+    }
+
+    /// Check the provider certificate according to the certificate policy.
+    fn check_certificate(&self, topic: Option<&str>) -> Result<(), CertificateError> {
+        if self.certificate_policy == CertificatePolicy::Ignore {
+            return Ok(());
+        }
+        let cert = self.certificate.borrow();
+        let cert = match *cert {
+            Some(ref c) => c,
+            None => return Ok(()),
+        };
+
+        let expiry = match cert.expires_in() {
+            None => Some(CertificateError::Expired),
+            Some(remaining) if remaining < self.certificate_min_validity => {
+                Some(CertificateError::ExpiresSoon {
+                    days: remaining.as_secs() / 86_400,
+                })
+            }
+            Some(_) => None,
+        };
+        let problems = expiry
+            .into_iter()
+            .chain(match topic {
+                Some(topic) if !cert.covers_topic(topic) => {
+                    Some(CertificateError::TopicNotCovered(topic.to_string()))
+                }
+                _ => None,
+            });
+
+        for problem in problems {
+            if self.certificate_policy == CertificatePolicy::Refuse {
+                return Err(problem);
+            }
+            // Only warn about the expiry once, but about every bad topic.
+            if let CertificateError::TopicNotCovered(_) = problem {
+                warn!("{}", problem);
+            } else if !self.expiry_warned.replace(true) {
+                warn!("{}", problem);
+            }
+        }
+        Ok(())
+    }
+
+    /// Configure a client side rate limit.
+    ///
+    /// Pass `None` to disable rate limiting (the default). The clients of an
+    /// `ApnsPool` share a single limit, see `ApnsPool::set_rate_limit`.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.rate_limiter = limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+    }
+
+    /// Returns the reason of the fatal API error that tripped the circuit
+    /// breaker, if it is open.
+    ///
+    /// While open, `send` fails immediately with `SendError::CircuitOpen`
+    /// without contacting the API.
+    pub fn circuit_open(&self) -> Option<ApiErrorReason> {
+        self.circuit.borrow().clone()
+    }
+
+    /// Close the circuit breaker, allowing requests to be sent again.
+    pub fn reset_circuit(&self) {
+        *self.circuit.borrow_mut() = None;
+    }
+
+    pub fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
+    /// Set the timeouts and connection settings.
+    ///
+    /// Sends that exceed a timeout fail with `SendError::Timeout`.
+    pub fn set_options(&mut self, options: ClientOptions) -> Result<(), Error> {
+        if self.options.proxy.is_some() && options.proxy.is_none() {
+            // Start with a fresh handle, since unsetting the proxy on the old
+            // one would also ignore the proxy environment variables.
+            let easy = Self::new_handle(self.auth.get_mut(), self.ca_certificate.as_deref())?;
+            *self.easy.get_mut() = easy;
+        }
+        self.options = options;
+        Ok(())
+    }
+
+    /// Send an HTTP/2 PING on the connection if it was idle for longer than
+    /// the ping interval of the `ClientOptions`.
+    ///
+    /// Call this periodically to keep idle connections alive, or use
+    /// `ApnsPool::spawn_health_check`. If the PING fails, the next send opens
+    /// a new connection.
+    pub fn upkeep(&self) -> Result<(), Error> {
+        if let Err(e) = self.easy.borrow().upkeep() {
+            self.reconnect.set(true);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Open the connection to the API before the first send.
+    ///
+    /// Sends an empty `GET` request, which APNS answers with an error
+    /// without delivering anything, and keeps the connection open for the
+    /// following sends.
+    pub fn connect(&self) -> Result<(), SendError> {
+        if self.delivery_disabled {
+            return Ok(());
+        }
+        self.reload_modified_credentials()?;
+
+        let url = self.build_url("", self.environment());
+        let mut easy = self.easy.borrow_mut();
+        easy.verbose(self.verbose || trace::connection_events_enabled())?;
+        easy.http_headers(List::new())?;
+        easy.get(true)?;
+        easy.url(&url)?;
+        self.perform(&mut easy, None)?;
+        debug!(target: trace::CONNECTION_TARGET, "Connected to {}", url);
+        Ok(())
+    }
+
+    /// Perform the prepared request, with the timeouts of the options.
+    fn perform(
+        &self,
+        easy: &mut Easy2<Collector>,
+        deadline: Option<Instant>,
+    ) -> Result<(), SendError> {
+        let timeout = match self.options.request_timeout_until(deadline) {
+            Some(timeout) => timeout,
+            None => {
+                let elapsed = self.options.total_timeout.unwrap_or_default();
+                return Err(TimeoutError { elapsed }.into());
+            }
+        };
+        easy.timeout(timeout)?;
+        self.options.apply(easy)?;
+        easy.fresh_connect(self.reconnect.get())?;
+        easy.get_mut().body.clear();
+        easy.get_mut().headers.clear();
+        if let Err(e) = easy.perform() {
+            // The connection may be broken, don't reuse it.
+            self.reconnect.set(true);
+            if e.is_operation_timedout() {
+                return Err(TimeoutError { elapsed: easy.total_time()? }.into());
+            }
+            return Err(e.into());
+        }
+        self.reconnect.set(false);
+        Ok(())
+    }
+
+    /// Retry sends that failed with a transient error.
+    /// Disabled by default.
+    pub fn set_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.retry_policy = policy;
+    }
+
+    /// Report sends to a metrics implementation, e.g. `PrometheusMetrics`.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<dyn Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Add an interceptor that runs around every send.
+    pub fn add_interceptor(&mut self, interceptor: Arc<dyn Interceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Remove all interceptors.
+    pub fn clear_interceptors(&mut self) {
+        self.interceptors.clear();
+    }
+
     /// *ATTENTION*: This completely disables actual communication with the
     /// APNS api.
     ///
@@ -119,11 +600,14 @@ impl ApnsSync {
     }
 
     /// Build the url for a device token.
-    fn build_url(&self, device_token: &str) -> String {
-        let root = if self.production {
-            APN_URL_PRODUCTION
-        } else {
-            APN_URL_DEV
+    fn build_url(&self, device_token: &str, environment: Environment) -> String {
+        let endpoint = self
+            .endpoint
+            .as_ref()
+            .or_else(|| self.environment_endpoints.get(&environment));
+        let root = match endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/'),
+            None => environment.url(),
         };
         format!("{}/3/device/{}", root, device_token)
     }
@@ -131,17 +615,183 @@ impl ApnsSync {
     /// Send a notification.
     /// Returns the UUID (either the configured one, or the one returned by the
     /// api).
-    pub fn send(&self, notification: Notification) -> Result<Uuid, SendError> {
-        let n = notification;
+    ///
+    /// Background notifications for which `validate_notification` reports
+    /// errors fail with `SendError::Validation` without being sent.
+    pub fn send(&self, n: Notification) -> Result<Uuid, SendError> {
+        self.send_detailed(n).map(|response| response.apns_id)
+    }
+
+    /// Send a notification, returning the apns-id and the environment that
+    /// accepted it.
+    pub fn send_detailed(&self, mut n: Notification) -> Result<SendResponse, SendError> {
+        if self.interceptors.is_empty() {
+            return self.send_intercepted(n);
+        }
+        for (index, interceptor) in self.interceptors.iter().enumerate() {
+            if let Some(mut result) = interceptor.before_send(&mut n) {
+                for interceptor in self.interceptors[..index].iter().rev() {
+                    interceptor.after_send(&n, &mut result);
+                }
+                return result;
+            }
+        }
 
+        let sent = n.clone();
+        let mut result = self.send_intercepted(n);
+        for interceptor in self.interceptors.iter().rev() {
+            interceptor.after_send(&sent, &mut result);
+        }
+        result
+    }
+
+    /// Send a notification that passed the interceptors.
+    fn send_intercepted(&self, n: Notification) -> Result<SendResponse, SendError> {
         // Just always generate a uuid client side for simplicity.
         let id = n.id.unwrap_or(Uuid::new_v4());
+        let environment = self.environment();
 
         if self.delivery_disabled {
-            return Ok(id);
+            return Ok(SendResponse {
+                apns_id: id,
+                environment,
+            });
+        }
+
+        let topic = self.resolve_topic(&n);
+        let push_type = n.push_type;
+        let span = SendSpan::new(&n, topic.as_deref(), id);
+        let result = span.in_scope(|| {
+            validate::check_background(&n)?;
+            // Fail fast, before waiting for the rate limit.
+            self.check_circuit()?;
+            // Once per notification, so that retries and the environment
+            // fallback are not rate limited.
+            let deadline = self.options.deadline();
+            if let Some(ref limiter) = self.rate_limiter {
+                limiter.acquire(&n.device_token, deadline)?;
+            }
+            self.deliver_with_fallback(&n, id, topic.as_deref(), deadline)
+        });
+        if let Err(SendError::Api(ref e)) = result {
+            if e.reason.is_fatal_auth() {
+                *self.circuit.borrow_mut() = Some(e.reason.clone());
+            }
+        }
+        let result = result.map(|environment| SendResponse {
+            apns_id: id,
+            environment,
+        });
+        if let Some(ref metrics) = self.metrics {
+            metrics.record_send(&SendEvent {
+                topic: topic.as_deref().unwrap_or_default(),
+                push_type,
+                outcome: SendOutcome::from_result(&result),
+                latency: span.elapsed(),
+            });
+        }
+        span.finish(&result);
+        result
+    }
+
+    /// Send a notification to the environment of the client, falling back to
+    /// the other environment if enabled and the device token (or
+    /// certificate) belongs to it.
+    fn deliver_with_fallback(
+        &self,
+        n: &Notification,
+        id: Uuid,
+        topic: Option<&str>,
+        deadline: Option<Instant>,
+    ) -> Result<Environment, SendError> {
+        let environment = self.environment();
+        let error = match self.deliver_with_retries(n, id, topic, environment, deadline) {
+            Ok(_) => return Ok(environment),
+            Err(e) => e,
+        };
+        if !self.environment_fallback || self.endpoint.is_some() || !is_environment_error(&error) {
+            return Err(error);
+        }
+
+        let other = environment.other();
+        debug!("Retrying notification {} in the {:?} environment", id, other);
+        match self.deliver_with_retries(n, id, topic, other, deadline) {
+            Ok(_) => Ok(other),
+            // The token is not valid in either environment.
+            Err(ref e) if is_environment_error(e) => Err(error),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Send a notification, retrying transient errors according to the
+    /// retry policy until the deadline.
+    ///
+    /// If the server closed the connection, the notification is sent again
+    /// on a new connection once, regardless of the retry policy.
+    fn deliver_with_retries(
+        &self,
+        n: &Notification,
+        id: Uuid,
+        topic: Option<&str>,
+        environment: Environment,
+        deadline: Option<Instant>,
+    ) -> Result<Uuid, SendError> {
+        let mut retry = 0;
+        let mut reconnected = false;
+        loop {
+            let result = self.deliver(n, id, environment, deadline);
+            if let Err(ref e) = result {
+                if !reconnected && retry::is_connection_lost(e) {
+                    debug!("Resending notification {} on a new connection: {}", id, Chain(e));
+                    if let Some(ref metrics) = self.metrics {
+                        metrics.record_retry(topic.unwrap_or_default());
+                    }
+                    self.reconnect.set(true);
+                    reconnected = true;
+                    continue;
+                }
+            }
+            let policy = match (&result, &self.retry_policy) {
+                (Err(e), Some(policy))
+                    if retry < policy.max_retries && retry::is_transient(e) =>
+                {
+                    policy
+                }
+                _ => return result,
+            };
+            // Wait at least as long as the server asked for.
+            let delay = match result.as_ref().err().and_then(SendError::retry_after) {
+                Some(retry_after) => retry_after.max(policy.delay(retry)),
+                None => policy.delay(retry),
+            };
+            if let Some(deadline) = deadline {
+                if Instant::now() + delay >= deadline {
+                    return result;
+                }
+            }
+            let error = result.unwrap_err();
+            warn!("Retrying notification {} after error: {}", id, Chain(&error));
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_retry(topic.unwrap_or_default());
+            }
+            thread::sleep(delay);
+            self.reconnect.set(true);
+            retry += 1;
         }
+    }
+
+    /// Send a notification with the given apns-id to the environment.
+    fn deliver(
+        &self,
+        n: &Notification,
+        id: Uuid,
+        environment: Environment,
+        deadline: Option<Instant>,
+    ) -> Result<Uuid, SendError> {
+        let topic = self.resolve_topic(n);
+        self.check_certificate(topic.as_deref())?;
 
-        let url = self.build_url(&n.device_token);
+        let url = self.build_url(&n.device_token, environment);
 
         // Add headers.
 
@@ -165,47 +815,232 @@ impl ApnsSync {
                 .map(|x| x.to_int().to_string())
                 .unwrap_or("".to_string())
         ))?;
-        headers.append(&format!("apns-topic:{}", n.topic))?;
+        headers.append(&format!("apns-topic:{}", topic.unwrap_or_default()))?;
+        headers.append(&format!(
+            "apns-push-type:{}",
+            n.push_type.map(|t| t.as_str()).unwrap_or("")
+        ))?;
         headers.append(&format!(
             "apns-collapse-id:{}",
-            n.collapse_id
+            n.collapse_id.as_ref()
                 .map(|x| x.as_str().to_string())
                 .unwrap_or("".to_string())
         ))?;
 
-        let request = ApnsRequest { aps: n.payload };
+        let request = ApnsRequest {
+            aps: n.payload.clone(),
+            data: n.data.clone(),
+        };
         let raw_request = ::serde_json::to_vec(&request)?;
 
-        let mut easy = self.easy.borrow_mut();
-
-        match &self.auth {
-            _ => {}
+        if let Auth::ProviderToken(ref key) = *self.auth.borrow() {
+            let token = self.token.borrow_mut().get(key)?;
+            headers.append(&format!("authorization:bearer {}", token))?;
         }
 
-        easy.verbose(self.verbose)?;
+        let mut easy = self.easy.borrow_mut();
+
+        easy.verbose(self.verbose || trace::connection_events_enabled())?;
         easy.http_headers(headers)?;
         easy.post(true)?;
         easy.post_fields_copy(&raw_request)?;
         easy.url(&url)?;
-        easy.perform()?;
+        self.perform(&mut easy, deadline)?;
 
         let status = easy.response_code()?;
         if status != 200 {
             // Request failed.
             // Read json response with the error.
-            let response_data = easy.get_ref();
-            let reason = ErrorResponse::parse_payload(&response_data.0);
-            Err(ApiError { status, reason }.into())
+            let response = easy.get_ref();
+            let reason = ErrorResponse::parse_payload(&response.body);
+            match reason {
+                ApiErrorReason::ExpiredProviderToken => self.token.borrow_mut().clear(),
+                // The server closes the connection.
+                ApiErrorReason::Shutdown | ApiErrorReason::IdleTimeout => self.reconnect.set(true),
+                _ => {}
+            }
+            let retry_after = response
+                .headers
+                .iter()
+                .find(|&(name, _)| name == "retry-after")
+                .and_then(|(_, value)| parse_retry_after(value, SystemTime::now()));
+            Err(ApiError {
+                status,
+                reason,
+                retry_after,
+                headers: response.headers.clone(),
+            }.into())
         } else {
             Ok(id)
         }
     }
 }
 
+/// Check if an error indicates that the notification was sent to the wrong
+/// environment.
+fn is_environment_error(error: &SendError) -> bool {
+    match error.as_api_error() {
+        Some(e) => matches!(
+            e.reason,
+            ApiErrorReason::BadDeviceToken | ApiErrorReason::BadCertificateEnvironment
+        ),
+        None => false,
+    }
+}
+
+/// Load the provider certificate information, if possible.
+fn load_certificate(auth: &Auth) -> Option<CertificateInfo> {
+    CertificateInfo::from_auth(auth).unwrap_or_else(|e| {
+        warn!("Could not read provider certificate: {}", Chain(&e));
+        None
+    })
+}
+
 #[cfg(test)]
 mod test {
-    use std::env::var;
+    use std::env::{temp_dir, var};
+    use std::fs;
+    use std::process;
+    use std::sync::Mutex;
     use super::*;
+    use test_util::generate_key;
+
+    /// A client with token authentication, shared by the tests of all
+    /// modules.
+    pub(crate) fn client() -> ApnsSync {
+        ApnsSync::with_token(ProviderToken::new("KEYID", "TEAMID", &generate_key())).unwrap()
+    }
+
+    #[test]
+    fn test_watch_token_key() {
+        let path = temp_dir().join(format!("apns2-test-{}.p8", process::id()));
+        fs::write(&path, generate_key()).unwrap();
+        let token = ProviderToken::from_file("KEYID", "TEAMID", &path).unwrap();
+        let mut apns = ApnsSync::with_token(token).unwrap();
+        apns.set_watch_credentials(true);
+
+        let get_token = |apns: &ApnsSync| match *apns.auth.borrow() {
+            Auth::ProviderToken(ref key) => apns.token.borrow_mut().get(key).unwrap(),
+            _ => unreachable!(),
+        };
+        let first = get_token(&apns);
+        apns.reload_modified_credentials().unwrap();
+        assert_eq!(get_token(&apns), first);
+
+        let key = generate_key();
+        fs::write(&path, &key).unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        apns.reload_modified_credentials().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        match *apns.auth.borrow() {
+            Auth::ProviderToken(ref token) => assert_eq!(token.key, key.as_bytes()),
+            _ => unreachable!(),
+        }
+        assert_ne!(get_token(&apns), first);
+        assert!(apns.reconnect.get());
+    }
+
+    #[test]
+    fn test_rate_limit_retries() {
+        let mut apns = client();
+        apns.set_endpoint(Some("https://127.0.0.1:1".to_string()));
+        apns.set_rate_limit(Some(
+            RateLimit::new(RateLimitMode::Reject).per_token_interval(Duration::from_secs(60)),
+        ));
+        apns.set_retry_policy(Some(
+            RetryPolicy::new(2).backoff(Duration::from_millis(1), Duration::from_millis(1)),
+        ));
+
+        let n = NotificationBuilder::new("com.example.app".to_string(), "abcdef".to_string())
+            .build();
+        match apns.send(n.clone()) {
+            Err(SendError::Transport(_)) => (),
+            other => panic!("Expected a transport error, got {:?}", other),
+        }
+        match apns.send(n) {
+            Err(SendError::RateLimited(_)) => (),
+            other => panic!("Expected the rate limit error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_circuit_before_rate_limit() {
+        let mut apns = client();
+        apns.set_rate_limit(Some(
+            RateLimit::new(RateLimitMode::Queue).per_token_interval(Duration::from_secs(60)),
+        ));
+        apns.rate_limiter.as_ref().unwrap().acquire("abcdef", None).unwrap();
+        *apns.circuit.borrow_mut() = Some(ApiErrorReason::InvalidProviderToken);
+
+        let started = Instant::now();
+        let n = NotificationBuilder::new("com.example.app".to_string(), "abcdef".to_string())
+            .build();
+        match apns.send(n) {
+            Err(SendError::CircuitOpen(_)) => (),
+            other => panic!("Expected an open circuit, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Records the outcome of each send.
+    #[derive(Default)]
+    struct Outcomes(Mutex<Vec<&'static str>>);
+
+    impl Metrics for Outcomes {
+        fn record_send(&self, event: &SendEvent) {
+            self.0.lock().unwrap().push(event.outcome.as_str());
+        }
+    }
+
+    #[test]
+    fn test_invalid_background() {
+        let mut apns = client();
+        let outcomes = Arc::new(Outcomes::default());
+        apns.set_metrics(Some(outcomes.clone()));
+
+        let n = NotificationBuilder::new("com.example.app".into(), "abcdef".into())
+            .push_type(PushType::Background)
+            .sound("default")
+            .build();
+        match apns.send(n) {
+            Err(SendError::Validation(e)) => assert_eq!(e.issues.len(), 3),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(*outcomes.0.lock().unwrap(), vec!["invalid"]);
+    }
+
+    #[test]
+    fn test_send_background_with_sound() {
+        let apns = client();
+        let n = NotificationBuilder::new("com.example.app".to_string(), "abcdef".to_string())
+            .background()
+            .sound("default")
+            .build();
+        assert_eq!(n.payload.sound.as_deref(), Some("default"));
+        match apns.send(n) {
+            Err(SendError::Validation(e)) => assert_eq!(e.issues.len(), 1),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_resolve_topic() {
+        let mut apns = client();
+        let mut n = NotificationBuilder::new("com.example.app".to_string(), "abcdef".to_string())
+            .build();
+        n.topic = None;
+        n.push_type = Some(PushType::Voip);
+        assert_eq!(apns.resolve_topic(&n), None);
+
+        apns.set_default_topic(Some("com.example.app".to_string()));
+        assert_eq!(apns.resolve_topic(&n).unwrap(), "com.example.app.voip");
+        apns.set_default_topic(Some("com.example.app.voip".to_string()));
+        assert_eq!(apns.resolve_topic(&n).unwrap(), "com.example.app.voip");
+        n.push_type = Some(PushType::Alert);
+        assert_eq!(apns.resolve_topic(&n).unwrap(), "com.example.app.voip");
+    }
 
     #[test]
     fn test_cert() {
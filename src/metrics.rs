@@ -0,0 +1,94 @@
+//! Pluggable metrics for sent notifications.
+
+use std::time::Duration;
+
+use error::{ApiErrorReason, SendError};
+use types::PushType;
+
+/// Outcome of a send, as reported to `Metrics`.
+#[derive(Clone, Debug)]
+pub enum SendOutcome {
+    /// The notification was accepted by the API.
+    Success,
+    /// The API rejected the notification.
+    Rejected(ApiErrorReason),
+    /// The client side rate limit was exceeded.
+    RateLimited,
+    /// The circuit breaker was open.
+    CircuitOpen,
+    /// The provider certificate failed the pre-send checks.
+    Certificate,
+    /// The request exceeded a timeout.
+    Timeout,
+    /// The notification was not sent because it failed validation.
+    Invalid,
+    /// The request failed, e.g. because of a connection error, or no client
+    /// was registered for the topic.
+    Error,
+}
+
+impl SendOutcome {
+    pub fn from_result<T>(result: &Result<T, SendError>) -> Self {
+        match *result {
+            Ok(_) => SendOutcome::Success,
+            Err(SendError::Api(ref e)) => SendOutcome::Rejected(e.reason.clone()),
+            Err(SendError::RateLimited(_)) => SendOutcome::RateLimited,
+            Err(SendError::CircuitOpen(_)) => SendOutcome::CircuitOpen,
+            Err(SendError::Certificate(_)) => SendOutcome::Certificate,
+            Err(SendError::Timeout(_)) => SendOutcome::Timeout,
+            Err(SendError::Validation(_)) => SendOutcome::Invalid,
+            Err(SendError::Routing(_))
+            | Err(SendError::Credential(_))
+            | Err(SendError::Transport(_))
+            | Err(SendError::Serialization(_))
+            | Err(SendError::Other(_)) => SendOutcome::Error,
+        }
+    }
+
+    /// Label for the outcome.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SendOutcome::Success => "success",
+            SendOutcome::Rejected(_) => "rejected",
+            SendOutcome::RateLimited => "rate_limited",
+            SendOutcome::CircuitOpen => "circuit_open",
+            SendOutcome::Certificate => "certificate",
+            SendOutcome::Timeout => "timeout",
+            SendOutcome::Invalid => "invalid",
+            SendOutcome::Error => "error",
+        }
+    }
+
+    /// Label for the reason of a rejection.
+    ///
+    /// Unknown reasons are reported as `Other` to bound the number of
+    /// distinct labels.
+    pub fn reason(&self) -> Option<String> {
+        match *self {
+            SendOutcome::Rejected(ApiErrorReason::Other(_)) => Some("Other".to_string()),
+            SendOutcome::Rejected(ref reason) => Some(reason.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// A completed send.
+#[derive(Clone, Debug)]
+pub struct SendEvent<'a> {
+    pub topic: &'a str,
+    pub push_type: Option<PushType>,
+    pub outcome: SendOutcome,
+    /// Time spent in `send`, including rate limiting and retries.
+    pub latency: Duration,
+}
+
+/// Receiver for client metrics.
+///
+/// Set with `ApnsSync::set_metrics`.
+pub trait Metrics: Send + Sync {
+    /// Called after each send.
+    fn record_send(&self, event: &SendEvent);
+
+    /// Called when a request is retried.
+    fn record_retry(&self, _topic: &str) {}
+}
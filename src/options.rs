@@ -0,0 +1,333 @@
+//! Timeouts and connection settings of the client.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use curl::easy::{Easy2, Handler};
+
+use auth::redacted;
+
+/// `CURLOPT_UPKEEP_INTERVAL_MS`, which is not exported by curl-sys.
+const CURLOPT_UPKEEP_INTERVAL_MS: ::curl_sys::CURLoption = ::curl_sys::CURLOPTTYPE_LONG + 281;
+
+/// Proxy to connect through.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Proxy {
+    /// Proxy URL. The scheme selects the proxy type: `http://`,
+    /// `https://`, `socks4://`, `socks5://` or `socks5h://` (resolving host
+    /// names on the proxy).
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Hosts to connect to directly, e.g. `localhost` or `.example.com`.
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    /// Tunnel the connection through HTTP proxies with `CONNECT`, which is
+    /// required for HTTP/2. Enabled by default.
+    #[serde(default = "default_tunnel")]
+    pub tunnel: bool,
+}
+
+fn default_tunnel() -> bool {
+    true
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .field("password", &redacted(&self.password))
+            .field("no_proxy", &self.no_proxy)
+            .field("tunnel", &self.tunnel)
+            .finish()
+    }
+}
+
+impl Proxy {
+    /// Create a proxy without authentication.
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Proxy {
+            url: url.into(),
+            username: None,
+            password: None,
+            no_proxy: Vec::new(),
+            tunnel: true,
+        }
+    }
+
+    /// Authenticate with the proxy.
+    pub fn auth<S: Into<String>>(mut self, username: S, password: S) -> Self {
+        self.username = Some(username.into());
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Add a host that is connected to directly.
+    pub fn no_proxy<S: Into<String>>(mut self, host: S) -> Self {
+        self.no_proxy.push(host.into());
+        self
+    }
+
+    /// Enable or disable the `CONNECT` tunnel.
+    pub fn tunnel(mut self, tunnel: bool) -> Self {
+        self.tunnel = tunnel;
+        self
+    }
+
+    fn apply<H: Handler>(&self, easy: &mut Easy2<H>) -> Result<(), ::curl::Error> {
+        easy.proxy(&self.url)?;
+        easy.proxy_username(self.username.as_deref().unwrap_or(""))?;
+        easy.proxy_password(self.password.as_deref().unwrap_or(""))?;
+        easy.noproxy(&self.no_proxy.join(","))?;
+        easy.http_proxy_tunnel(self.tunnel)
+    }
+}
+
+/// Timeouts and connection settings of a client.
+///
+/// Unset options use the defaults of libcurl: no timeouts, no TCP
+/// keepalive, a PING interval of 60 seconds and a maximum idle time of 118
+/// seconds. Without a proxy, the `https_proxy` and `no_proxy` environment
+/// variables are used.
+#[derive(Clone, Debug, Default)]
+pub struct ClientOptions {
+    /// Maximum time for establishing a connection, including the TLS
+    /// handshake.
+    pub connect_timeout: Option<Duration>,
+    /// Maximum time for a single request, including connecting.
+    pub request_timeout: Option<Duration>,
+    /// Maximum time for a send, including waiting for the rate limit,
+    /// retries and the environment fallback.
+    pub total_timeout: Option<Duration>,
+    /// Interval of TCP keepalive probes on idle connections.
+    pub tcp_keepalive: Option<Duration>,
+    /// Minimum interval between two HTTP/2 PING frames sent by
+    /// `ApnsSync::upkeep`.
+    pub ping_interval: Option<Duration>,
+    /// Maximum time a connection may be idle before a new one is opened.
+    pub max_idle: Option<Duration>,
+    pub proxy: Option<Proxy>,
+}
+
+impl ClientOptions {
+    /// Create options with the defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    pub fn total_timeout(mut self, timeout: Duration) -> Self {
+        self.total_timeout = Some(timeout);
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, interval: Duration) -> Self {
+        self.tcp_keepalive = Some(interval);
+        self
+    }
+
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = Some(interval);
+        self
+    }
+
+    pub fn max_idle(mut self, max_idle: Duration) -> Self {
+        self.max_idle = Some(max_idle);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Deadline of a send starting now.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.total_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Timeout of the next request of a send with the given deadline.
+    ///
+    /// Returns `None` if the deadline has passed.
+    pub(crate) fn request_timeout_until(&self, deadline: Option<Instant>) -> Option<Duration> {
+        let remaining = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return None;
+                }
+                Some(deadline - now)
+            }
+            None => None,
+        };
+        match (self.request_timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => Some(timeout.or(remaining).unwrap_or_default()),
+        }
+    }
+
+    /// Apply the connection settings to a curl handle.
+    pub(crate) fn apply<H: Handler>(&self, easy: &mut Easy2<H>) -> Result<(), ::curl::Error> {
+        easy.connect_timeout(self.connect_timeout.unwrap_or_default())?;
+        easy.tcp_keepalive(self.tcp_keepalive.is_some())?;
+        if let Some(interval) = self.tcp_keepalive {
+            easy.tcp_keepidle(interval)?;
+            easy.tcp_keepintvl(interval)?;
+        }
+        easy.maxage_conn(self.max_idle.unwrap_or(Duration::from_secs(118)))?;
+        if let Some(ref proxy) = self.proxy {
+            proxy.apply(easy)?;
+        }
+
+        let ping_interval = self.ping_interval.unwrap_or(Duration::from_secs(60));
+        let code = unsafe {
+            ::curl_sys::curl_easy_setopt(
+                easy.raw(),
+                CURLOPT_UPKEEP_INTERVAL_MS,
+                ping_interval.as_millis() as ::std::os::raw::c_long,
+            )
+        };
+        if code != ::curl_sys::CURLE_OK {
+            return Err(::curl::Error::new(code));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+    use NotificationBuilder;
+
+    /// A proxy that records the request head of each connection and refuses
+    /// it.
+    struct TestProxy {
+        url: String,
+        requests: Arc<Mutex<Vec<String>>>,
+        done: Arc<AtomicBool>,
+        thread: JoinHandle<()>,
+    }
+
+    impl TestProxy {
+        fn start() -> TestProxy {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let done = Arc::new(AtomicBool::new(false));
+
+            let (recorded, stop) = (requests.clone(), done.clone());
+            let thread = thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let mut stream = match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        Err(_) => {
+                            thread::sleep(Duration::from_millis(10));
+                            continue;
+                        }
+                    };
+                    stream.set_nonblocking(false).unwrap();
+                    let mut head = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    recorded.lock().unwrap().push(String::from_utf8_lossy(&head).into_owned());
+                    let response = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+                    let _ = stream.write_all(response);
+                }
+            });
+            TestProxy { url, requests, done, thread }
+        }
+
+        /// Stop the proxy and return the recorded requests.
+        fn stop(self) -> Vec<String> {
+            self.done.store(true, Ordering::SeqCst);
+            self.thread.join().unwrap();
+            Arc::try_unwrap(self.requests).unwrap().into_inner().unwrap()
+        }
+    }
+
+    fn send_through(endpoint: &str, proxy: Proxy) -> ::ApnsSync {
+        let mut apns = ::test::client();
+        apns.set_endpoint(Some(endpoint.to_string()));
+        apns.set_options(ClientOptions::new().proxy(proxy)).unwrap();
+        let n = NotificationBuilder::new("com.example.app".to_string(), "abcdef".to_string())
+            .build();
+        assert!(apns.send(n).is_err());
+        apns
+    }
+
+    #[test]
+    fn test_proxy() {
+        let proxy = TestProxy::start();
+        let mut apns = send_through(
+            "https://apns.invalid",
+            Proxy::new(&*proxy.url).auth("user", "pass"),
+        );
+
+        // Removing the proxy connects directly again.
+        apns.set_options(ClientOptions::new()).unwrap();
+        assert!(apns.options().proxy.is_none());
+        apns.set_endpoint(Some("https://127.0.0.1:1".to_string()));
+        let n = NotificationBuilder::new("com.example.app".to_string(), "abcdef".to_string())
+            .build();
+        assert!(apns.send(n).is_err());
+
+        let requests = proxy.stop();
+        assert_eq!(requests.len(), 1, "{:?}", requests);
+        let request = &requests[0];
+        assert!(request.starts_with("CONNECT apns.invalid:443 HTTP/1.1\r\n"), "{}", request);
+        assert!(request.contains("\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n"), "{}", request);
+    }
+
+    #[test]
+    fn test_proxy_without_tunnel() {
+        let proxy = TestProxy::start();
+        send_through("http://apns.invalid", Proxy::new(&*proxy.url).tunnel(false));
+
+        let requests = proxy.stop();
+        assert_eq!(requests.len(), 1, "{:?}", requests);
+        let request = &requests[0];
+        assert!(request.starts_with("POST http://apns.invalid/3/device/abcdef "), "{}", request);
+    }
+
+    #[test]
+    fn test_no_proxy() {
+        let proxy = TestProxy::start();
+        send_through("https://127.0.0.1:1", Proxy::new(&*proxy.url).no_proxy("127.0.0.1"));
+        assert!(proxy.stop().is_empty());
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let options = ClientOptions::new().request_timeout(Duration::from_secs(5));
+        assert_eq!(options.request_timeout_until(None), Some(Duration::from_secs(5)));
+
+        let deadline = Some(Instant::now() + Duration::from_secs(1));
+        assert!(options.request_timeout_until(deadline).unwrap() <= Duration::from_secs(1));
+        assert_eq!(options.request_timeout_until(Some(Instant::now())), None);
+
+        let options = ClientOptions::new();
+        assert_eq!(options.request_timeout_until(None), Some(Duration::from_secs(0)));
+    }
+}
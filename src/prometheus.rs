@@ -0,0 +1,190 @@
+//! Metrics in the Prometheus text exposition format.
+//!
+//! Requires the `prometheus` feature.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use metrics::{Metrics, SendEvent};
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    /// (topic, push type, outcome, reason) -> count
+    sends: BTreeMap<(String, String, String, String), u64>,
+    /// (topic, push type) -> latency
+    latency: BTreeMap<(String, String), Histogram>,
+    /// topic -> count
+    retries: BTreeMap<String, u64>,
+}
+
+/// `Metrics` implementation that renders the Prometheus text format.
+///
+/// Serve the output of `render` on the metrics endpoint of the application.
+#[derive(Default)]
+pub struct PrometheusMetrics {
+    registry: Mutex<Registry>,
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render all metrics in the text exposition format.
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+
+        out.push_str("# HELP apns2_notifications_total Notifications sent, by outcome.\n");
+        out.push_str("# TYPE apns2_notifications_total counter\n");
+        for ((topic, push_type, outcome, reason), count) in &registry.sends {
+            let _ = writeln!(
+                out,
+                "apns2_notifications_total{{topic=\"{}\",push_type=\"{}\",outcome=\"{}\",reason=\"{}\"}} {}",
+                escape(topic),
+                escape(push_type),
+                outcome,
+                escape(reason),
+                count
+            );
+        }
+
+        out.push_str("# HELP apns2_send_duration_seconds Latency of sends.\n");
+        out.push_str("# TYPE apns2_send_duration_seconds histogram\n");
+        for ((topic, push_type), histogram) in &registry.latency {
+            let labels = format!(
+                "topic=\"{}\",push_type=\"{}\"",
+                escape(topic),
+                escape(push_type)
+            );
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "apns2_send_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "apns2_send_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(out, "apns2_send_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+            let _ = writeln!(
+                out,
+                "apns2_send_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+
+        out.push_str("# HELP apns2_retries_total Requests retried.\n");
+        out.push_str("# TYPE apns2_retries_total counter\n");
+        for (topic, count) in &registry.retries {
+            let _ = writeln!(out, "apns2_retries_total{{topic=\"{}\"}} {}", escape(topic), count);
+        }
+        out
+    }
+}
+
+impl Metrics for PrometheusMetrics {
+    fn record_send(&self, event: &SendEvent) {
+        let push_type = event.push_type.map(|t| t.as_str()).unwrap_or("");
+        let mut registry = self.registry.lock().unwrap_or_else(|e| e.into_inner());
+
+        let key = (
+            event.topic.to_string(),
+            push_type.to_string(),
+            event.outcome.as_str().to_string(),
+            event.outcome.reason().unwrap_or_default(),
+        );
+        *registry.sends.entry(key).or_insert(0) += 1;
+
+        let seconds = event.latency.as_secs_f64();
+        let histogram = registry
+            .latency
+            .entry((event.topic.to_string(), push_type.to_string()))
+            .or_insert_with(|| Histogram {
+                buckets: vec![0; LATENCY_BUCKETS.len()],
+                ..Histogram::default()
+            });
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter_mut()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    fn record_retry(&self, topic: &str) {
+        let mut registry = self.registry.lock().unwrap_or_else(|e| e.into_inner());
+        *registry.retries.entry(topic.to_string()).or_insert(0) += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    use error::ApiErrorReason;
+    use metrics::SendOutcome;
+    use types::PushType;
+
+    #[test]
+    fn test_render() {
+        let metrics = PrometheusMetrics::new();
+        for outcome in [
+            SendOutcome::Success,
+            SendOutcome::Success,
+            SendOutcome::Rejected(ApiErrorReason::BadDeviceToken),
+        ] {
+            metrics.record_send(&SendEvent {
+                topic: "com.example.app",
+                push_type: Some(PushType::Alert),
+                outcome,
+                latency: Duration::from_millis(20),
+            });
+        }
+        metrics.record_retry("com.example.app");
+
+        let out = metrics.render();
+        let labels = "topic=\"com.example.app\",push_type=\"alert\"";
+        assert!(out.contains(&format!(
+            "apns2_notifications_total{{{},outcome=\"success\",reason=\"\"}} 2",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "apns2_notifications_total{{{},outcome=\"rejected\",reason=\"BadDeviceToken\"}} 1",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "apns2_send_duration_seconds_bucket{{{},le=\"0.01\"}} 0",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "apns2_send_duration_seconds_bucket{{{},le=\"0.025\"}} 3",
+            labels
+        )));
+        assert!(out.contains("apns2_retries_total{topic=\"com.example.app\"} 1"));
+    }
+}
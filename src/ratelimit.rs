@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error::RateLimitError;
+
+/// Number of tracked device tokens after which stale entries are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Behaviour when a notification would exceed the configured rate limit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RateLimitMode {
+    /// Block the calling thread until the notification may be sent. Fails
+    /// with `SendError::RateLimited` if the wait would exceed
+    /// `ClientOptions::total_timeout`, which includes the time spent
+    /// waiting.
+    Queue,
+    /// Fail immediately with `SendError::RateLimited`.
+    Reject,
+}
+
+/// Client side rate limit configuration.
+///
+/// APNS throttles providers that send too many notifications, either to a
+/// single device (`TooManyRequests`) or in total. The limiter prevents the
+/// client from ever reaching those limits.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// Maximum number of requests per second, across all device tokens.
+    /// `Some(0)` means no limit, like `None`.
+    pub requests_per_second: Option<u32>,
+    /// Minimum interval between two notifications to the same device token.
+    pub per_token_interval: Option<Duration>,
+    pub mode: RateLimitMode,
+}
+
+impl RateLimit {
+    /// Create a new rate limit without any restrictions.
+    pub fn new(mode: RateLimitMode) -> Self {
+        RateLimit {
+            requests_per_second: None,
+            per_token_interval: None,
+            mode,
+        }
+    }
+
+    /// Limit the total amount of requests per second. 0 disables the limit.
+    pub fn requests_per_second(mut self, rps: u32) -> Self {
+        self.requests_per_second = if rps > 0 { Some(rps) } else { None };
+        self
+    }
+
+    /// Set the minimum interval between notifications for the same device.
+    pub fn per_token_interval(mut self, interval: Duration) -> Self {
+        self.per_token_interval = Some(interval);
+        self
+    }
+}
+
+/// Rate limiter that can be shared by several clients, e.g. the clients of
+/// an `ApnsPool`.
+pub(crate) struct RateLimiter {
+    mode: RateLimitMode,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(mut config: RateLimit) -> Self {
+        config.requests_per_second = config.requests_per_second.filter(|&rps| rps > 0);
+        let tokens = config.requests_per_second.unwrap_or(0) as f64;
+        RateLimiter {
+            mode: config.mode,
+            state: Mutex::new(LimiterState {
+                config,
+                tokens,
+                refilled_at: Instant::now(),
+                last_sent: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Wait for (or reject) a request slot for the given device token.
+    ///
+    /// Waiting happens without holding the lock, so other device tokens
+    /// are not held up. A wait past the deadline is rejected.
+    pub fn acquire(
+        &self,
+        device_token: &str,
+        deadline: Option<Instant>,
+    ) -> Result<(), RateLimitError> {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                let wait = state.wait_time(device_token, Instant::now());
+                if wait == Duration::from_secs(0) {
+                    state.consume(device_token, Instant::now());
+                    return Ok(());
+                }
+                wait
+            };
+            let past_deadline = deadline.is_some_and(|deadline| Instant::now() + wait > deadline);
+            match self.mode {
+                RateLimitMode::Queue if !past_deadline => thread::sleep(wait),
+                _ => return Err(RateLimitError { retry_after: wait }),
+            }
+        }
+    }
+}
+
+/// Token bucket for the global limit combined with a per device token
+/// timestamp map.
+struct LimiterState {
+    config: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+    last_sent: HashMap<String, Instant>,
+}
+
+impl LimiterState {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rps) = self.config.requests_per_second {
+            let elapsed = duration_secs(now.duration_since(self.refilled_at));
+            self.tokens = (self.tokens + elapsed * rps as f64).min(rps as f64);
+        }
+        self.refilled_at = now;
+    }
+
+    /// Time until a request for the device token would be allowed.
+    fn wait_time(&mut self, device_token: &str, now: Instant) -> Duration {
+        self.refill(now);
+
+        let global = match self.config.requests_per_second {
+            Some(rps) if self.tokens < 1.0 => {
+                let secs = (1.0 - self.tokens) / rps as f64;
+                Duration::from_millis((secs * 1000.0).ceil() as u64)
+            }
+            _ => Duration::from_secs(0),
+        };
+
+        let per_token = match (self.config.per_token_interval, self.last_sent.get(device_token)) {
+            (Some(interval), Some(last)) => {
+                let elapsed = now.duration_since(*last);
+                if elapsed < interval {
+                    interval - elapsed
+                } else {
+                    Duration::from_secs(0)
+                }
+            }
+            _ => Duration::from_secs(0),
+        };
+
+        global.max(per_token)
+    }
+
+    fn consume(&mut self, device_token: &str, now: Instant) {
+        self.refill(now);
+        if self.config.requests_per_second.is_some() {
+            self.tokens = (self.tokens - 1.0).max(0.0);
+        }
+
+        if let Some(interval) = self.config.per_token_interval {
+            if self.last_sent.len() >= PRUNE_THRESHOLD {
+                self.last_sent
+                    .retain(|_, last| now.duration_since(*last) < interval);
+            }
+            self.last_sent.insert(device_token.to_string(), now);
+        }
+    }
+}
+
+fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reject_per_token() {
+        let limit = RateLimit::new(RateLimitMode::Reject)
+            .per_token_interval(Duration::from_secs(60));
+        let limiter = RateLimiter::new(limit);
+
+        assert!(limiter.acquire("a", None).is_ok());
+        assert!(limiter.acquire("b", None).is_ok());
+        let err = limiter.acquire("a", None).unwrap_err();
+        assert!(err.retry_after > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn test_reject_global() {
+        let limit = RateLimit::new(RateLimitMode::Reject).requests_per_second(2);
+        let limiter = RateLimiter::new(limit);
+
+        assert!(limiter.acquire("a", None).is_ok());
+        assert!(limiter.acquire("b", None).is_ok());
+        assert!(limiter.acquire("c", None).is_err());
+    }
+
+    #[test]
+    fn test_zero_requests_per_second() {
+        let limit = RateLimit::new(RateLimitMode::Queue).requests_per_second(0);
+        assert!(limit.requests_per_second.is_none());
+        let mut limit = RateLimit::new(RateLimitMode::Queue);
+        limit.requests_per_second = Some(0);
+        let limiter = RateLimiter::new(limit);
+        for _ in 0..10 {
+            assert!(limiter.acquire("a", None).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_queue_deadline() {
+        let limit = RateLimit::new(RateLimitMode::Queue)
+            .per_token_interval(Duration::from_secs(60));
+        let limiter = RateLimiter::new(limit);
+        let deadline = Some(Instant::now() + Duration::from_millis(100));
+
+        assert!(limiter.acquire("a", deadline).is_ok());
+        let err = limiter.acquire("a", deadline).unwrap_err();
+        assert!(err.retry_after > Duration::from_secs(59));
+    }
+}
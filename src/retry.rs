@@ -0,0 +1,104 @@
+use std::cmp;
+use std::time::Duration;
+
+use error::{ApiErrorReason, SendError};
+
+/// Retry behaviour for sends that failed with a transient error.
+///
+/// Transient errors are connection failures, timeouts and the
+/// `InternalServerError`, `ServiceUnavailable`, `Shutdown` and `IdleTimeout`
+/// API errors. Retries use a new connection and an exponential backoff.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the first attempt.
+    pub max_retries: u32,
+    /// Delay before the first retry, in milliseconds.
+    pub initial_backoff_ms: u64,
+    /// Upper bound for the delay, in milliseconds.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 5_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Create a policy with the default backoff.
+    pub fn new(max_retries: u32) -> Self {
+        RetryPolicy {
+            max_retries,
+            ..Self::default()
+        }
+    }
+
+    /// Set the initial and maximum backoff.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff_ms = initial.as_millis() as u64;
+        self.max_backoff_ms = max.as_millis() as u64;
+        self
+    }
+
+    /// Delay before the given retry, starting at 0.
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u64.checked_shl(retry).unwrap_or(u64::MAX);
+        let delay = self.initial_backoff_ms.saturating_mul(factor);
+        Duration::from_millis(cmp::min(delay, self.max_backoff_ms))
+    }
+}
+
+/// Check if a send failed with an error that might not occur again.
+pub(crate) fn is_transient(error: &SendError) -> bool {
+    match *error {
+        SendError::Api(ref e) => matches!(
+            e.reason,
+            ApiErrorReason::InternalServerError
+                | ApiErrorReason::ServiceUnavailable
+                | ApiErrorReason::Shutdown
+                | ApiErrorReason::IdleTimeout
+        ),
+        SendError::Timeout(_) => true,
+        SendError::Transport(_) => true,
+        _ => false,
+    }
+}
+
+/// Check if a send failed because the server closed the connection, e.g.
+/// with a GOAWAY frame, before the notification was accepted.
+pub(crate) fn is_connection_lost(error: &SendError) -> bool {
+    match *error {
+        SendError::Api(ref e) => matches!(
+            e.reason,
+            ApiErrorReason::Shutdown | ApiErrorReason::IdleTimeout
+        ),
+        SendError::Transport(ref e) => {
+            e.is_http2_error()
+                || e.is_http2_stream_error()
+                || e.is_got_nothing()
+                || e.is_send_error()
+                || e.is_recv_error()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new(10)
+            .backoff(Duration::from_millis(100), Duration::from_secs(1));
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(400));
+        assert_eq!(policy.delay(4), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+    }
+}
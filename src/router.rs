@@ -0,0 +1,338 @@
+//! Sending through multiple clients, selected by topic.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use auth::Auth;
+use config::Environment;
+use error::{ApiErrorReason, Error, RoutingError, SendError};
+use ratelimit::{RateLimit, RateLimiter};
+use types::{Notification, SendResponse};
+use ApnsSync;
+
+/// A pool of clients with the same credentials, each with its own
+/// connection.
+///
+/// Unlike `ApnsSync`, the pool can be shared between threads.
+pub struct ApnsPool {
+    clients: Vec<Mutex<ApnsSync>>,
+    next: AtomicUsize,
+}
+
+impl ApnsPool {
+    /// Create a pool from clients that use the same credentials and
+    /// environment.
+    ///
+    /// The rate limit of the first client applies to the whole pool, and is
+    /// shared by all clients.
+    ///
+    /// Panics if `clients` is empty.
+    pub fn new(mut clients: Vec<ApnsSync>) -> Self {
+        assert!(!clients.is_empty(), "ApnsPool requires at least one client");
+        let limiter = clients[0].rate_limiter.clone();
+        for apns in &mut clients[1..] {
+            apns.rate_limiter = limiter.clone();
+        }
+        ApnsPool {
+            clients: clients.into_iter().map(Mutex::new).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The environment of the clients.
+    pub fn environment(&self) -> Environment {
+        self.lock(0).environment()
+    }
+
+    /// Topics the credentials are valid for: the topics of the provider
+    /// certificate, or the default topic.
+    fn topics(&self) -> Vec<String> {
+        let apns = self.lock(0);
+        match apns.certificate() {
+            Some(ref info) if !info.topics.is_empty() => info.topics.clone(),
+            _ => apns.default_topic().into_iter().collect(),
+        }
+    }
+
+    fn lock(&self, index: usize) -> MutexGuard<'_, ApnsSync> {
+        self.clients[index]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Configure a client side rate limit for the whole pool, shared by all
+    /// clients. See `ApnsSync::set_rate_limit`.
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        let limiter = limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+        for index in 0..self.clients.len() {
+            self.lock(index).rate_limiter = limiter.clone();
+        }
+    }
+
+    /// Replace the credentials of all clients. See `ApnsSync::set_auth`.
+    pub fn set_auth(&self, auth: Auth) -> Result<(), Error> {
+        for index in 0..self.clients.len() {
+            self.lock(index).set_auth(auth.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Enable/disable watching the credential files of all clients. See
+    /// `ApnsSync::set_watch_credentials`.
+    pub fn set_watch_credentials(&self, watch: bool) {
+        for index in 0..self.clients.len() {
+            self.lock(index).set_watch_credentials(watch);
+        }
+    }
+
+    /// Returns the reason of the fatal API error that tripped the circuit
+    /// breaker of a client, if any is open.
+    pub fn circuit_open(&self) -> Option<ApiErrorReason> {
+        (0..self.clients.len()).find_map(|index| self.lock(index).circuit_open())
+    }
+
+    /// Close the circuit breakers of all clients.
+    pub fn reset_circuit(&self) {
+        for index in 0..self.clients.len() {
+            self.lock(index).reset_circuit();
+        }
+    }
+
+    /// Open the connections of all clients. See `ApnsSync::connect`.
+    pub fn connect(&self) -> Result<(), SendError> {
+        for index in 0..self.clients.len() {
+            self.lock(index).connect()?;
+        }
+        Ok(())
+    }
+
+    /// Send HTTP/2 PINGs on the connections of the idle clients. See
+    /// `ApnsSync::upkeep`.
+    ///
+    /// Clients that are sending are skipped, their connection is in use.
+    pub fn upkeep(&self) {
+        for client in &self.clients {
+            if let Ok(apns) = client.try_lock() {
+                if let Err(e) = apns.upkeep() {
+                    warn!("Connection health check failed: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Start a thread that calls `upkeep` at the interval, until the pool
+    /// is dropped.
+    ///
+    /// Use an interval below the ping interval of the `ClientOptions`, which
+    /// limits how often PINGs are actually sent.
+    pub fn spawn_health_check(pool: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let pool: Weak<Self> = Arc::downgrade(pool);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match pool.upgrade() {
+                Some(pool) => pool.upkeep(),
+                None => return,
+            }
+        })
+    }
+
+    /// Send with the next idle client, or wait for the next client in turn
+    /// if all are busy.
+    pub fn send(&self, notification: Notification) -> Result<Uuid, SendError> {
+        self.send_detailed(notification)
+            .map(|response| response.apns_id)
+    }
+
+    /// Like `send`, but also returns the environment that accepted the
+    /// notification. See `ApnsSync::send_detailed`.
+    pub fn send_detailed(&self, notification: Notification) -> Result<SendResponse, SendError> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.clients.len();
+        for i in 0..count {
+            if let Ok(apns) = self.clients[(start + i) % count].try_lock() {
+                return apns.send_detailed(notification);
+            }
+        }
+        self.lock(start % count).send_detailed(notification)
+    }
+}
+
+/// Dispatches notifications to the client registered for their topic.
+///
+/// Register one client (or pool) per set of credentials. A client with a
+/// token signing key can serve all apps of the team, so all of their topics
+/// share its connection.
+pub struct ApnsRouter {
+    routes: HashMap<(String, Environment), Arc<ApnsPool>>,
+    environment: Environment,
+}
+
+impl ApnsRouter {
+    /// Create a router that sends to the given environment by default.
+    pub fn new(environment: Environment) -> Self {
+        ApnsRouter {
+            routes: HashMap::new(),
+            environment,
+        }
+    }
+
+    /// Register a client for the topics.
+    ///
+    /// If `topics` is empty, the topics of the provider certificate or the
+    /// default topic of the client are used.
+    pub fn add_client(&mut self, client: ApnsSync, topics: &[&str]) -> Arc<ApnsPool> {
+        let pool = Arc::new(ApnsPool::new(vec![client]));
+        self.add_pool(pool.clone(), topics);
+        pool
+    }
+
+    /// Register a pool for the topics, in the environment of the pool.
+    ///
+    /// If `topics` is empty, the topics of the provider certificate or the
+    /// default topic of the clients are used.
+    pub fn add_pool(&mut self, pool: Arc<ApnsPool>, topics: &[&str]) {
+        let topics = if topics.is_empty() {
+            pool.topics()
+        } else {
+            topics.iter().map(|t| t.to_string()).collect()
+        };
+        let environment = pool.environment();
+        for topic in topics {
+            self.routes.insert((topic, environment), pool.clone());
+        }
+    }
+
+    /// All registered topics and their environment.
+    pub fn topics(&self) -> Vec<(&str, Environment)> {
+        self.routes
+            .keys()
+            .map(|&(ref topic, environment)| (topic.as_str(), environment))
+            .collect()
+    }
+
+    /// Find the pool for a topic.
+    ///
+    /// Topics with the suffix of the push type (e.g. `.voip`) fall back to
+    /// the pool of the bundle id.
+    fn route(&self, n: &Notification, environment: Environment) -> Option<&Arc<ApnsPool>> {
+        let topic = n.topic.as_ref()?;
+        if let Some(pool) = self.routes.get(&(topic.clone(), environment)) {
+            return Some(pool);
+        }
+        let suffix = n.push_type.map(|t| t.topic_suffix()).unwrap_or("");
+        if suffix.is_empty() || !topic.ends_with(suffix) {
+            return None;
+        }
+        let bundle_id = topic[..topic.len() - suffix.len()].to_string();
+        self.routes.get(&(bundle_id, environment))
+    }
+
+    /// Send a notification in the default environment.
+    ///
+    /// Fails with `SendError::Routing` if no client is registered for the
+    /// topic of the notification.
+    pub fn send(&self, notification: Notification) -> Result<Uuid, SendError> {
+        self.send_to(notification, self.environment)
+    }
+
+    /// Send a notification in the given environment.
+    pub fn send_to(
+        &self,
+        notification: Notification,
+        environment: Environment,
+    ) -> Result<Uuid, SendError> {
+        self.send_detailed_to(notification, environment)
+            .map(|response| response.apns_id)
+    }
+
+    /// Like `send`, but also returns the environment that accepted the
+    /// notification. See `ApnsSync::send_detailed`.
+    pub fn send_detailed(&self, notification: Notification) -> Result<SendResponse, SendError> {
+        self.send_detailed_to(notification, self.environment)
+    }
+
+    /// Like `send_to`, but also returns the environment that accepted the
+    /// notification.
+    pub fn send_detailed_to(
+        &self,
+        notification: Notification,
+        environment: Environment,
+    ) -> Result<SendResponse, SendError> {
+        match self.route(&notification, environment) {
+            Some(pool) => pool.send_detailed(notification),
+            None => Err(RoutingError {
+                topic: notification.topic,
+                environment,
+            }.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use auth::ProviderToken;
+    use ratelimit::RateLimitMode;
+    use types::{NotificationBuilder, PushType};
+
+    fn client(production: bool) -> ApnsSync {
+        let mut apns = ::test::client();
+        apns.set_production(production);
+        apns.disable_delivery_for_testing();
+        apns
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut router = ApnsRouter::new(Environment::Production);
+        router.add_client(client(true), &["com.example.a", "com.example.b"]);
+        router.add_client(client(false), &["com.example.a"]);
+
+        let n = |topic: &str| NotificationBuilder::new(topic.into(), "abcdef".into());
+        assert!(router.send(n("com.example.b").build()).is_ok());
+        assert!(router.send_to(n("com.example.a").build(), Environment::Sandbox).is_ok());
+        let voip = n("com.example.a.voip").push_type(PushType::Voip).build();
+        assert!(router.send(voip).is_ok());
+        let response = router.send_detailed_to(n("com.example.a").build(), Environment::Sandbox);
+        assert_eq!(response.unwrap().environment, Environment::Sandbox);
+
+        match router.send_to(n("com.example.b").build(), Environment::Sandbox) {
+            Err(SendError::Routing(e)) => assert_eq!(e.topic, Some("com.example.b".into())),
+            _ => panic!("Expected a routing error"),
+        }
+    }
+
+    #[test]
+    fn test_pool() {
+        let limit = RateLimit::new(RateLimitMode::Reject).requests_per_second(1);
+        let clients = (0..2)
+            .map(|_| {
+                let mut apns = client(true);
+                apns.set_rate_limit(Some(limit.clone()));
+                apns
+            })
+            .collect();
+        let pool = ApnsPool::new(clients);
+
+        let limiter = |index| pool.lock(index).rate_limiter.clone().unwrap();
+        assert!(limiter(0).acquire("a", None).is_ok());
+        assert!(limiter(1).acquire("b", None).is_err());
+        pool.set_rate_limit(None);
+        assert!(pool.lock(1).rate_limiter.is_none());
+
+        *pool.lock(1).circuit.borrow_mut() = Some(ApiErrorReason::InvalidProviderToken);
+        assert!(pool.circuit_open().is_some());
+        pool.reset_circuit();
+        assert!(pool.circuit_open().is_none());
+
+        *pool.lock(0).circuit.borrow_mut() = Some(ApiErrorReason::InvalidProviderToken);
+        pool.set_auth(Auth::ProviderToken(ProviderToken::new("KEYID2", "TEAMID", "")))
+            .unwrap();
+        assert!(pool.circuit_open().is_none());
+    }
+}
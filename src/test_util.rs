@@ -0,0 +1,14 @@
+//! Helpers shared by the unit tests and the emulator tests, which include
+//! this file with `#[path]`. Only `std` and `openssl` can be used here.
+
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+
+/// A new P-256 private key in PKCS#8 PEM format, like the .p8 files of
+/// provider tokens.
+pub fn generate_key() -> String {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap()
+}
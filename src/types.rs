@@ -1,5 +1,11 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
 use uuid::Uuid;
 
+use config::Environment;
+use error::ValidationError;
+use validate::{check_issues, validate_notification, Issue};
+
 /// APNS production endpoint.
 pub static APN_URL_PRODUCTION: &'static str = "https://api.push.apple.com";
 
@@ -26,8 +32,55 @@ impl Priority {
     }
 }
 
-#[derive(Fail, Debug)]
-#[fail(display = "CollapseId too long (must be at most 64 bytes)")]
+/// The type of a notification, sent in the `apns-push-type` header.
+///
+/// Required for watchOS 6 and later, recommended for all notifications.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PushType {
+    Alert,
+    Background,
+    Location,
+    Voip,
+    Complication,
+    FileProvider,
+    Mdm,
+    LiveActivity,
+    PushToTalk,
+}
+
+impl PushType {
+    /// The value of the `apns-push-type` header.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PushType::Alert => "alert",
+            PushType::Background => "background",
+            PushType::Location => "location",
+            PushType::Voip => "voip",
+            PushType::Complication => "complication",
+            PushType::FileProvider => "fileprovider",
+            PushType::Mdm => "mdm",
+            PushType::LiveActivity => "liveactivity",
+            PushType::PushToTalk => "pushtotalk",
+        }
+    }
+
+    /// Suffix that is appended to the app bundle id to form the topic.
+    pub fn topic_suffix(self) -> &'static str {
+        match self {
+            PushType::Location => ".location-query",
+            PushType::Voip => ".voip",
+            PushType::Complication => ".complication",
+            PushType::FileProvider => ".pushkit.fileprovider",
+            PushType::LiveActivity => ".push-type.liveactivity",
+            PushType::PushToTalk => ".voip-ptt",
+            PushType::Alert | PushType::Background | PushType::Mdm => "",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("CollapseId too long (must be at most 64 bytes)")]
 pub struct CollapseIdTooLongError;
 
 /// Wrapper type for collapse ids.
@@ -113,18 +166,70 @@ pub struct Payload {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sound: Option<String>,
     /// Set to true to mark the app as having content available.
-    #[serde(rename = "content-available", skip_serializing_if = "Option::is_none")]
+    /// Sent as `"content-available": 1`, as required by APNS.
+    #[serde(
+        rename = "content-available",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "content_available"
+    )]
     pub content_available: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub category: Option<String>,
     #[serde(rename = "thread-id", skip_serializing_if = "Option::is_none")]
     pub thread_id: Option<String>,
+    /// Additional `aps` keys not covered by the fields above.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// (De)serializes `content-available` as the number APNS expects, while
+/// also accepting booleans.
+mod content_available {
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::Serializer;
+    use serde_json::Value;
+
+    pub fn serialize<S>(value: &Option<bool>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match *value {
+            Some(flag) => serializer.serialize_u8(flag as u8),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(match Option::<Value>::deserialize(deserializer)? {
+            Some(Value::Bool(flag)) => Some(flag),
+            Some(Value::Number(n)) => Some(n.as_u64() != Some(0)),
+            _ => None,
+        })
+    }
 }
 
 /// A full json request object for sending a notification to the API.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct ApnsRequest {
     pub aps: Payload,
+    /// Custom data, sent as top level keys next to `aps`.
+    #[serde(flatten)]
+    pub data: Map<String, Value>,
+}
+
+/// Result of a successful send.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SendResponse {
+    pub apns_id: Uuid,
+    /// The environment that accepted the notification.
+    ///
+    /// Differs from the environment of the client if the notification was
+    /// sent with the environment fallback.
+    pub environment: Environment,
 }
 
 /// A notification struct contains all relevant data for a notification request
@@ -134,9 +239,15 @@ pub(crate) struct ApnsRequest {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Notification {
     /// The topic to use. Usually the app bundle id.
-    pub topic: String,
+    /// If not set, the default topic of the client is used.
+    pub topic: Option<String>,
     pub device_token: String,
     pub payload: Payload,
+    /// The push type.
+    pub push_type: Option<PushType>,
+    /// Custom data for the app, sent outside the `aps` dictionary.
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub data: Map<String, Value>,
 
     /// Optional id identifying the message.
     pub id: Option<Uuid>,
@@ -151,9 +262,11 @@ impl Notification {
     /// Create a new notification.
     pub fn new(topic: String, device_token: String, payload: Payload) -> Self {
         Notification {
-            topic,
+            topic: Some(topic),
             device_token,
             payload,
+            push_type: None,
+            data: Map::new(),
             id: None,
             expiration: None,
             priority: None,
@@ -162,23 +275,112 @@ impl Notification {
     }
 }
 
+/// Kind of notification built by a `NotificationBuilder`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Mode {
+    Standard,
+    Voip,
+    Background,
+}
+
 /// A builder for convenient construction of notifications.
 pub struct NotificationBuilder {
     notification: Notification,
+    mode: Mode,
 }
 
 impl NotificationBuilder {
     pub fn new(topic: String, device_id: String) -> Self {
         NotificationBuilder {
             notification: Notification::new(topic, device_id, Payload::default()),
+            mode: Mode::Standard,
         }
     }
 
+    /// Create a builder for a notification without a topic.
+    /// The default topic of the client will be used.
+    pub fn for_device(device_id: String) -> Self {
+        let mut notification = Notification::new(String::new(), device_id, Payload::default());
+        notification.topic = None;
+        NotificationBuilder {
+            notification,
+            mode: Mode::Standard,
+        }
+    }
+
+    /// Build a PushKit VoIP notification.
+    ///
+    /// Sets the `voip` push type, priority 10 and an expiration of 0, so
+    /// the call is delivered immediately or not at all. The `.voip` suffix
+    /// is appended to the topic by `build`. Pass the call information with
+    /// `data` or `custom_data`.
+    pub fn voip(mut self) -> Self {
+        self.mode = Mode::Voip;
+        self.notification.push_type = Some(PushType::Voip);
+        self.notification.priority = Some(Priority::High);
+        self.notification.expiration = Some(0);
+        self
+    }
+
+    /// Build a background (silent) notification.
+    ///
+    /// Sets `content-available`, the `background` push type and priority
+    /// 5. APNS requires background notifications to have no alert, badge or
+    /// sound; `try_build` fails if any of them is set, or if the push type
+    /// or priority is changed, and `send` rejects background notifications
+    /// with any of them. `build` does not check, so prefer `try_build`.
+    pub fn background(mut self) -> Self {
+        self.mode = Mode::Background;
+        self.notification.push_type = Some(PushType::Background);
+        self.notification.priority = Some(Priority::Low);
+        self.notification.payload.content_available = Some(true);
+        self
+    }
+
+    pub fn topic<S: Into<String>>(mut self, topic: S) -> Self {
+        self.notification.topic = Some(topic.into());
+        self
+    }
+
+    pub fn push_type(mut self, push_type: PushType) -> Self {
+        self.notification.push_type = Some(push_type);
+        self
+    }
+
     pub fn payload(mut self, payload: Payload) -> Self {
         self.notification.payload = payload;
         self
     }
 
+    /// Use a raw APNS JSON payload, as sent to the API.
+    ///
+    /// The `aps` dictionary becomes the payload, all other top level keys
+    /// become custom data.
+    pub fn raw_payload(mut self, json: &str) -> Result<Self, ::serde_json::Error> {
+        let request: ApnsRequest = ::serde_json::from_str(json)?;
+        self.notification.payload = request.aps;
+        self.notification.data = request.data;
+        Ok(self)
+    }
+
+    /// Add a custom data key, sent outside the `aps` dictionary.
+    pub fn data<S: Into<String>>(mut self, key: S, value: Value) -> Self {
+        self.notification.data.insert(key.into(), value);
+        self
+    }
+
+    /// Add the fields of a serializable struct (or map) as custom data.
+    pub fn custom_data<T: Serialize>(mut self, data: &T) -> Result<Self, ::serde_json::Error> {
+        match ::serde_json::to_value(data)? {
+            Value::Object(fields) => self.notification.data.extend(fields),
+            _ => {
+                use serde::ser::Error;
+                return Err(::serde_json::Error::custom("custom data is not an object"));
+            }
+        }
+        Ok(self)
+    }
+
     pub fn alert<S: Into<String>>(mut self, alert: S) -> Self {
         self.notification.payload.alert = Some(Alert::Simple(alert.into()));
         self
@@ -257,7 +459,40 @@ impl NotificationBuilder {
         self
     }
 
-    pub fn build(self) -> Notification {
+    /// Build the notification as is, without validating it. See
+    /// `try_build`.
+    pub fn build(mut self) -> Notification {
+        if self.mode == Mode::Voip {
+            if let Some(ref mut topic) = self.notification.topic {
+                if !topic.ends_with(PushType::Voip.topic_suffix()) {
+                    topic.push_str(PushType::Voip.topic_suffix());
+                }
+            }
+        }
         self.notification
     }
+
+    /// Build the notification, failing if `validate_notification` reports
+    /// errors, or if the push type does not match the `voip` or
+    /// `background` mode.
+    pub fn try_build(self) -> Result<Notification, ValidationError> {
+        let expected = match self.mode {
+            Mode::Standard => None,
+            Mode::Voip => Some(PushType::Voip),
+            Mode::Background => Some(PushType::Background),
+        };
+        let notification = self.build();
+        let mut issues = validate_notification(&notification);
+        if let Some(expected) = expected {
+            if notification.push_type != Some(expected) {
+                issues.push(Issue::error(format!(
+                    "Push type {} conflicts with the {} mode",
+                    notification.push_type.map(|t| t.as_str()).unwrap_or("none"),
+                    expected.as_str()
+                )));
+            }
+        }
+        check_issues(issues)?;
+        Ok(notification)
+    }
 }
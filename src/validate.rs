@@ -0,0 +1,391 @@
+//! Offline validation of notifications against the rules of the APNS api.
+
+use std::fmt;
+
+use serde_json::{Map, Value};
+
+use error::ValidationError;
+use types::{ApnsRequest, Notification, Priority, PushType};
+
+/// Maximum payload size for VoIP notifications.
+pub const MAX_VOIP_PAYLOAD_SIZE: usize = 5 * 1024;
+/// Maximum payload size for all other notifications.
+pub const MAX_PAYLOAD_SIZE: usize = 4 * 1024;
+/// Maximum length of a collapse id.
+pub const MAX_COLLAPSE_ID_LENGTH: usize = 64;
+
+/// Keys the APNS api understands in the `aps` dictionary.
+const APS_KEYS: &[&str] = &[
+    "alert",
+    "badge",
+    "sound",
+    "thread-id",
+    "category",
+    "content-available",
+    "mutable-content",
+    "target-content-id",
+    "interruption-level",
+    "relevance-score",
+    "filter-criteria",
+    "stale-date",
+    "content-state",
+    "timestamp",
+    "event",
+    "dismissal-date",
+    "attributes-type",
+    "attributes",
+    "url-args",
+];
+
+const INTERRUPTION_LEVELS: &[&str] = &["passive", "active", "time-sensitive", "critical"];
+
+/// Localization argument keys that require a matching localization key.
+const LOC_KEY_PAIRS: &[(&str, &str)] = &[
+    ("loc-args", "loc-key"),
+    ("title-loc-args", "title-loc-key"),
+    ("subtitle-loc-args", "subtitle-loc-key"),
+];
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Severity {
+    /// The notification is accepted, but might not behave as intended.
+    Warning,
+    /// The notification will be rejected or dropped.
+    Error,
+}
+
+/// A problem found while validating a notification.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Issue {
+    pub(crate) fn error<S: Into<String>>(message: S) -> Self {
+        Issue {
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+
+    fn warning<S: Into<String>>(message: S) -> Self {
+        Issue {
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Validate a notification without sending it.
+pub fn validate_notification(n: &Notification) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    if n.device_token.is_empty() {
+        issues.push(Issue::error("Missing device token"));
+    } else if !n.device_token.chars().all(|c| c.is_ascii_hexdigit()) {
+        issues.push(Issue::warning("Device token is not a hex string"));
+    }
+    if let (Some(topic), Some(push_type)) = (n.topic.as_ref(), n.push_type) {
+        let suffix = push_type.topic_suffix();
+        if !suffix.is_empty() && !topic.ends_with(suffix) {
+            issues.push(Issue::error(format!(
+                "{} notifications require a topic ending in {}",
+                push_type.as_str(),
+                suffix
+            )));
+        }
+    }
+    if n.push_type == Some(PushType::Voip) && n.priority == Some(Priority::Low) {
+        issues.push(Issue::warning("VoIP notifications should use priority 10"));
+    }
+    if let Some(ref id) = n.collapse_id {
+        if id.as_str().len() > MAX_COLLAPSE_ID_LENGTH {
+            issues.push(Issue::error(format!(
+                "Collapse id is {} bytes long (maximum: {})",
+                id.as_str().len(),
+                MAX_COLLAPSE_ID_LENGTH
+            )));
+        }
+    }
+
+    let request = ApnsRequest {
+        aps: n.payload.clone(),
+        data: n.data.clone(),
+    };
+    match ::serde_json::to_value(&request) {
+        Ok(payload) => issues.extend(validate_payload(&payload, n.push_type, n.priority)),
+        Err(e) => issues.push(Issue::error(format!("Payload can not be serialized: {}", e))),
+    }
+    issues
+}
+
+/// Fail with the errors of `validate_notification`, ignoring warnings.
+pub(crate) fn check_notification(n: &Notification) -> Result<(), ValidationError> {
+    check_issues(validate_notification(n))
+}
+
+/// Fail with the errors of `validate_notification` for background
+/// notifications, which APNS accepts but devices ignore when malformed.
+pub(crate) fn check_background(n: &Notification) -> Result<(), ValidationError> {
+    if n.push_type == Some(PushType::Background) {
+        check_notification(n)
+    } else {
+        Ok(())
+    }
+}
+
+/// Fail with the errors among the issues, ignoring warnings.
+pub(crate) fn check_issues(issues: Vec<Issue>) -> Result<(), ValidationError> {
+    let issues: Vec<Issue> = issues.into_iter().filter(Issue::is_error).collect();
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError { issues })
+    }
+}
+
+/// Validate a raw APNS JSON payload, as sent in the request body.
+///
+/// The push type and priority are sent as headers, and are required to
+/// check the rules specific to them.
+pub fn validate_payload(
+    payload: &Value,
+    push_type: Option<PushType>,
+    priority: Option<Priority>,
+) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    let size = ::serde_json::to_vec(payload).map(|v| v.len()).unwrap_or(0);
+    let max_size = match push_type {
+        Some(PushType::Voip) => MAX_VOIP_PAYLOAD_SIZE,
+        _ => MAX_PAYLOAD_SIZE,
+    };
+    if size > max_size {
+        issues.push(Issue::error(format!(
+            "Payload is {} bytes (maximum: {})",
+            size, max_size
+        )));
+    }
+
+    let empty = Map::new();
+    let aps = match payload.get("aps") {
+        Some(Value::Object(aps)) => aps,
+        Some(_) => {
+            issues.push(Issue::error("aps is not a dictionary"));
+            &empty
+        }
+        None => {
+            issues.push(Issue::error("Missing aps dictionary"));
+            &empty
+        }
+    };
+
+    for key in aps.keys() {
+        if !APS_KEYS.contains(&key.as_str()) {
+            issues.push(Issue::warning(format!("Unknown aps key: {}", key)));
+        }
+    }
+
+    if let Some(level) = aps.get("interruption-level") {
+        let valid = level
+            .as_str()
+            .map(|l| INTERRUPTION_LEVELS.contains(&l))
+            .unwrap_or(false);
+        if !valid {
+            issues.push(Issue::error(format!(
+                "Invalid interruption-level {} (expected one of {})",
+                level,
+                INTERRUPTION_LEVELS.join(", ")
+            )));
+        }
+    }
+
+    if let Some(Value::Object(alert)) = aps.get("alert") {
+        for &(args, key) in LOC_KEY_PAIRS {
+            if alert.contains_key(args) && !alert.contains_key(key) {
+                issues.push(Issue::error(format!("{} without {}", args, key)));
+            }
+        }
+    }
+
+    let content_available = aps.get("content-available").and_then(|v| v.as_u64()) == Some(1);
+    let has_alert = aps.contains_key("alert")
+        || aps.contains_key("badge")
+        || aps.contains_key("sound");
+
+    match push_type {
+        Some(PushType::Background) => {
+            if !content_available {
+                issues.push(Issue::error(
+                    "Background notifications require content-available: 1",
+                ));
+            }
+            if priority != Some(Priority::Low) {
+                issues.push(Issue::error("Background notifications require priority 5"));
+            }
+            if has_alert {
+                issues.push(Issue::error(
+                    "Background notifications must not contain alert, badge or sound",
+                ));
+            }
+        }
+        Some(PushType::Alert) if !has_alert => {
+            issues.push(Issue::warning(
+                "Alert notifications should contain an alert, badge or sound",
+            ));
+        }
+        None if content_available && !has_alert && priority != Some(Priority::Low) => {
+            issues.push(Issue::warning(
+                "Silent notifications should use priority 5 and push type background",
+            ));
+        }
+        _ => {}
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use types::{CollapseId, NotificationBuilder};
+
+    fn messages(issues: &[Issue]) -> Vec<String> {
+        issues.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn test_valid_notification() {
+        let n = NotificationBuilder::new("com.example".into(), "abcdef".into())
+            .title("title")
+            .push_type(PushType::Alert)
+            .collapse_id(CollapseId::new("id".into()).unwrap())
+            .build();
+        assert!(validate_notification(&n).is_empty());
+    }
+
+    #[test]
+    fn test_background_rules() {
+        let n = NotificationBuilder::new("com.example".into(), "abcdef".into())
+            .push_type(PushType::Background)
+            .sound("default")
+            .build();
+        let issues = messages(&validate_notification(&n));
+        assert_eq!(
+            issues,
+            vec![
+                "error: Background notifications require content-available: 1",
+                "error: Background notifications require priority 5",
+                "error: Background notifications must not contain alert, badge or sound",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_content_available() {
+        let n = NotificationBuilder::new("com.example".into(), "abcdef".into())
+            .content_available()
+            .push_type(PushType::Background)
+            .priority(Priority::Low)
+            .build();
+        let errors: Vec<_> = validate_notification(&n)
+            .into_iter()
+            .filter(Issue::is_error)
+            .collect();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let request = ApnsRequest {
+            aps: n.payload.clone(),
+            data: n.data.clone(),
+        };
+        let json = ::serde_json::to_value(&request).unwrap();
+        assert_eq!(json["aps"]["content-available"], 1);
+        let parsed: ApnsRequest = ::serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.aps.content_available, Some(true));
+    }
+
+    #[test]
+    fn test_voip() {
+        #[derive(Serialize)]
+        struct Call {
+            caller: &'static str,
+        }
+
+        let n = NotificationBuilder::new("com.example".into(), "abcdef".into())
+            .voip()
+            .custom_data(&Call { caller: "Alice" })
+            .unwrap()
+            .try_build()
+            .unwrap();
+        assert_eq!(n.topic, Some("com.example.voip".into()));
+        assert_eq!(n.expiration, Some(0));
+        assert_eq!(n.data["caller"], "Alice");
+
+        let n = NotificationBuilder::new("com.example".into(), "abcdef".into())
+            .push_type(PushType::Voip)
+            .priority(Priority::Low)
+            .build();
+        let issues = messages(&validate_notification(&n));
+        assert_eq!(
+            issues,
+            vec![
+                "error: voip notifications require a topic ending in .voip",
+                "warning: VoIP notifications should use priority 10",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_background() {
+        let n = NotificationBuilder::new("com.example".into(), "abcdef".into())
+            .background()
+            .data("sync", json!(true))
+            .try_build()
+            .unwrap();
+        assert_eq!(n.push_type, Some(PushType::Background));
+        assert_eq!(n.priority, Some(Priority::Low));
+        assert!(validate_notification(&n).is_empty());
+
+        let result = NotificationBuilder::new("com.example".into(), "abcdef".into())
+            .background()
+            .sound("default")
+            .try_build();
+        assert!(result.is_err());
+        let result = NotificationBuilder::new("com.example".into(), "abcdef".into())
+            .background()
+            .push_type(PushType::Alert)
+            .try_build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_raw_payload() {
+        let payload = json!({
+            "aps": {
+                "alert": { "loc-args": ["a"] },
+                "interruption-level": "urgent",
+                "foo": 1,
+            },
+            "data": "x".repeat(MAX_PAYLOAD_SIZE),
+        });
+        let issues = validate_payload(&payload, None, None);
+        assert_eq!(issues.len(), 4);
+        assert!(issues.iter().all(|i| i.is_error() || i.message.contains("foo")));
+        // VoIP allows a larger payload.
+        assert_eq!(validate_payload(&payload, Some(PushType::Voip), None).len(), 3);
+    }
+}